@@ -0,0 +1,81 @@
+-- Wireshark dissector for the KCP packets in captures written by Ekho (see ekho::capture).
+--
+--   wireshark -X lua_script:contrib/kcp.lua ekho.pcapng
+--
+-- or copy this file into the personal Lua plugins directory shown under Help > About Wireshark >
+-- Folders. Captured packets are carried in UDP to or from port 7; each packet holds one or more
+-- segments, each starting with a 24-byte little-endian header:
+--
+--   0       4     5     6       8       12      16      20      24
+--   | conv  | cmd | frg | wnd   | ts    | sn    | una   | len   | data (len bytes)
+
+local kcp = Proto("kcp", "KCP")
+
+local PORT = 7
+local HEADER = 24
+
+local commands = {
+    [81] = "PUSH",
+    [82] = "ACK",
+    [83] = "WASK",
+    [84] = "WINS",
+    -- Ekho extensions
+    [85] = "DATAGRAM",
+    [86] = "FIN",
+    [87] = "RST",
+}
+
+local fields = kcp.fields
+fields.conv = ProtoField.uint32("kcp.conv", "Conversation", base.DEC)
+fields.cmd = ProtoField.uint8("kcp.cmd", "Command", base.DEC, commands)
+fields.frg = ProtoField.uint8("kcp.frg", "Fragments after this one", base.DEC)
+fields.wnd = ProtoField.uint16("kcp.wnd", "Receive window", base.DEC)
+fields.ts = ProtoField.uint32("kcp.ts", "Timestamp", base.DEC)
+fields.sn = ProtoField.uint32("kcp.sn", "Sequence number", base.DEC)
+fields.una = ProtoField.uint32("kcp.una", "Unacknowledged", base.DEC)
+fields.len = ProtoField.uint32("kcp.len", "Length", base.DEC)
+fields.data = ProtoField.bytes("kcp.data", "Data")
+
+local truncated = ProtoExpert.new("kcp.truncated", "Segment shorter than its header says",
+    expert.group.MALFORMED, expert.severity.ERROR)
+kcp.experts = { truncated }
+
+function kcp.dissector(buffer, pinfo, tree)
+    if buffer:len() < HEADER then
+        return 0
+    end
+    pinfo.cols.protocol = kcp.name
+    local summary = {}
+    local offset = 0
+    while buffer:len() - offset >= HEADER do
+        local cmd = buffer(offset + 4, 1):uint()
+        local sn = buffer(offset + 12, 4):le_uint()
+        local len = buffer(offset + 20, 4):le_uint()
+        local size = math.min(HEADER + len, buffer:len() - offset)
+        local name = commands[cmd] or tostring(cmd)
+
+        local segment = tree:add(kcp, buffer(offset, size))
+        segment:append_text(string.format(", %s, sn %d, len %d", name, sn, len))
+        segment:add_le(fields.conv, buffer(offset, 4))
+        segment:add(fields.cmd, buffer(offset + 4, 1))
+        segment:add(fields.frg, buffer(offset + 5, 1))
+        segment:add_le(fields.wnd, buffer(offset + 6, 2))
+        segment:add_le(fields.ts, buffer(offset + 8, 4))
+        segment:add_le(fields.sn, buffer(offset + 12, 4))
+        segment:add_le(fields.una, buffer(offset + 16, 4))
+        segment:add_le(fields.len, buffer(offset + 20, 4))
+        if size > HEADER then
+            segment:add(fields.data, buffer(offset + HEADER, size - HEADER))
+        end
+        if size < HEADER + len then
+            segment:add_proto_expert_info(truncated)
+        end
+
+        table.insert(summary, string.format("%s sn=%d", name, sn))
+        offset = offset + size
+    end
+    pinfo.cols.info = string.format("conv %d: %s", buffer(0, 4):le_uint(), table.concat(summary, ", "))
+    return offset
+end
+
+DissectorTable.get("udp.port"):add(PORT, kcp)
@@ -25,26 +25,51 @@ CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
 //! behind receive buffers (as opposed to a naive linked list in original implementation) and using
 //! the BBR congestion control algorithm instead of the naive loss-based congestion control.
 //!
-//! This is 100% compatible with other KCP implementations.
+//! The wire format is that of `ikcp.c`, which `tests/ikcp_vectors.rs` checks segment by segment.
+//! The only differences are the unreliable datagrams (see
+//! [send_datagram](ControlBlock::send_datagram)) and the explicit closing of connections (see
+//! [State]), which use commands of their own.
+//!
+//! The control block does no I/O and reads no clock. Like `ikcp_update`, [update] tells it
+//! the current time, which every other call then works with. Feed packets from the network to
+//! [input], and send whatever [output] yields after [flush].
+//!
+//! # Features
+//!
+//! - `serde`: [Config] can be deserialized, and [Stats] serialized.
+//! - `tracing`: spans around the main entry points, and debug events from congestion control.
+//!
+//! [update]: ControlBlock::update
+//! [input]: ControlBlock::input
+//! [output]: ControlBlock::output
+//! [flush]: ControlBlock::flush
 
-mod pcc;
+/// `tracing::debug!`, or nothing without the `tracing` feature.
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        #[cfg(feature = "tracing")]
+        tracing::debug!($($arg)*);
+    };
+}
+
+pub mod pcc;
 mod timer;
 mod window;
 
-use crate::kcp::pcc::{MonitorInterval, PCC};
+use crate::pcc::{MonitorInterval, Pcc};
 use bytes::{Buf, BufMut};
 use derivative::Derivative;
 use num_enum::{IntoPrimitive, TryFromPrimitive};
-use rand::{thread_rng, Rng};
-use serde::Deserialize;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
 use std::cell::RefCell;
 use std::cmp::{max, min};
 use std::collections::VecDeque;
 use std::convert::TryInto;
 use std::rc::Rc;
-use std::time::Instant;
 use thiserror::Error;
 use timer::Timer;
+#[cfg(feature = "tracing")]
 use tracing::instrument;
 use window::Window;
 
@@ -59,12 +84,42 @@ pub enum Error {
     InvalidCommand(u8),
     #[error("empty queue (try again later)")]
     NotAvailable,
+    #[error("too much data in flight")]
+    Congested,
+    #[error("connection closed")]
+    Closed,
     #[error("wrong conv. (expected {expected}, found {found})")]
     WrongConv { expected: u32, found: u32 },
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
 
+/// A problem with a configuration value, found by [Config::validate].
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error("{field}: {message}")]
+pub struct ConfigError {
+    /// The path of the offending field, e.g. `pcc.eps_min`.
+    pub field: String,
+    pub message: String,
+}
+
+impl ConfigError {
+    pub fn new(field: impl Into<String>, message: impl Into<String>) -> Self {
+        ConfigError {
+            field: field.into(),
+            message: message.into(),
+        }
+    }
+
+    /// Prefixes the field path with the section the configuration is found in.
+    pub fn within(self, section: &str) -> Self {
+        ConfigError {
+            field: format!("{}.{}", section, self.field),
+            ..self
+        }
+    }
+}
+
 /// The overhead imposed by KCP per packet (aka. packet header length).
 const OVERHEAD: u32 = 24;
 /// The upper bound for fragmentation of a long payload.
@@ -82,14 +137,40 @@ enum Command {
     Ack = 82,
     AskWnd = 83,
     TellWnd = 84,
+    /// Ekho extension: an unreliable datagram, which is neither sequenced nor acknowledged.
+    Datagram = 85,
+    /// Ekho extension: the end of stream, sequenced and retransmitted like a PUSH.
+    Fin = 86,
+    /// Ekho extension: aborts the connection right away.
+    Rst = 87,
+}
+
+/// Closing state of a connection.
+///
+/// Each side ends its sending direction with a FIN, which is delivered in order after all the
+/// data sent before it. Either side can also abort the connection with a RST, which takes effect
+/// immediately and discards everything still in flight.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize), serde(rename_all = "snake_case"))]
+pub enum State {
+    /// Both directions are open.
+    Established,
+    /// We have sent a FIN, but the other side may still send data.
+    FinWait,
+    /// The application has received the other side's FIN, but may still send data.
+    CloseWait,
+    /// Both directions are finished.
+    Closed,
+    /// The connection has been aborted by either side.
+    Reset,
 }
 
 /// KCP configuration.
 ///
 /// All time-related items are in milliseconds.
-#[derive(Clone, Debug, Deserialize, Derivative)]
+#[derive(Clone, Debug, Derivative)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize), serde(default))]
 #[derivative(Default)]
-#[serde(default)]
 pub struct Config {
     #[derivative(Default(value = "536"))]
     pub mtu: u32,
@@ -141,12 +222,138 @@ pub struct Config {
     pub bdp_gain: usize,
     #[derivative(Default(value = "None"))]
     pub pcc: Option<pcc::Config>,
+    /// If set, the window is probed after this long without hearing from the other side, so that
+    /// dead links are detected even when no data flows.
+    #[derivative(Default(value = "None"))]
+    pub keepalive: Option<u32>,
+    /// With keepalive on, the link is considered to be dead after this long without hearing from
+    /// the other side.
+    #[derivative(Default(value = "60000"))]
+    pub keepalive_timeout: u32,
 }
 
 impl Config {
     pub fn mss(&self) -> usize {
         (self.mtu - OVERHEAD) as usize
     }
+
+    /// The largest payload that can be passed to [send](ControlBlock::send) at once.
+    pub fn max_payload(&self) -> usize {
+        self.mss() * MAX_FRAGMENTS as usize
+    }
+
+    /// Checks the configuration for values a control block cannot work with, and returns every
+    /// problem found.
+    pub fn validate(&self) -> Vec<ConfigError> {
+        let mut errors = Vec::new();
+        let mut check = |ok: bool, field: &str, message: String| {
+            if !ok {
+                errors.push(ConfigError::new(field, message));
+            }
+        };
+        check(
+            self.mtu > OVERHEAD,
+            "mtu",
+            format!("must be larger than the {}-byte KCP header", OVERHEAD),
+        );
+        check(self.interval > 0, "interval", "must be positive".into());
+        check(self.send_wnd > 0, "send_wnd", "must be positive".into());
+        check(self.recv_wnd > 0, "recv_wnd", "must be positive".into());
+        check(
+            self.rto_min <= self.rto_max,
+            "rto_max",
+            format!("must be at least rto_min ({})", self.rto_min),
+        );
+        check(
+            self.rto_min <= self.rto_default && self.rto_default <= self.rto_max,
+            "rto_default",
+            format!(
+                "must be between rto_min ({}) and rto_max ({})",
+                self.rto_min, self.rto_max
+            ),
+        );
+        check(self.probe_min > 0, "probe_min", "must be positive".into());
+        check(
+            self.probe_min <= self.probe_max,
+            "probe_max",
+            format!("must be at least probe_min ({})", self.probe_min),
+        );
+        check(
+            self.dead_link_thres > 0,
+            "dead_link_thres",
+            "must be positive".into(),
+        );
+        check(
+            self.fast_resend_thres != Some(0),
+            "fast_resend_thres",
+            "must be positive (leave it out to turn fast retransmission off)".into(),
+        );
+        check(
+            self.rt_prop_wnd > 0,
+            "rt_prop_wnd",
+            "must be positive".into(),
+        );
+        check(self.btl_bw_wnd > 0, "btl_bw_wnd", "must be positive".into());
+        check(self.bdp_gain > 0, "bdp_gain", "must be positive".into());
+        if let Some(keepalive) = self.keepalive {
+            check(
+                keepalive > 0,
+                "keepalive",
+                "must be positive (leave it out to turn keepalive off)".into(),
+            );
+            check(
+                keepalive < self.keepalive_timeout,
+                "keepalive_timeout",
+                format!("must be longer than keepalive ({})", keepalive),
+            );
+        }
+        if let Some(pcc) = &self.pcc {
+            errors.extend(pcc.validate().into_iter().map(|err| err.within("pcc")));
+        }
+        errors
+    }
+}
+
+/// A snapshot of the state of a [ControlBlock], see [stats](ControlBlock::stats).
+///
+/// Counters are cumulative since the control block was created; times are in milliseconds.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct Stats {
+    /// Segments sent for the first time.
+    pub segments_sent: u64,
+    /// Segments retransmitted after their RTO expired.
+    pub segments_resent: u64,
+    /// Segments retransmitted early because later ones had been acknowledged.
+    pub segments_fast_resent: u64,
+    /// Payload bytes acknowledged by the other side.
+    pub bytes_acked: u64,
+    /// Payload bytes handed to the application by [recv](ControlBlock::recv).
+    pub bytes_received: u64,
+    /// Retransmissions (timed out or fast) per segment sent. Spurious retransmissions count too,
+    /// so this overestimates the loss rate.
+    pub retransmit_ratio: f64,
+    /// The PCC sending rate (unit: kB/s), if PCC is enabled.
+    pub send_rate: Option<f64>,
+    /// The PCC phase, if PCC is enabled.
+    pub pcc_phase: Option<&'static str>,
+    pub srtt: u32,
+    pub rtt_var: u32,
+    pub rto: u32,
+    /// Bytes sent but not yet acknowledged, headers included.
+    pub inflight: usize,
+    /// The receive window last advertised by the other side (unit: segments).
+    pub rmt_wnd: u16,
+    /// Segments enqueued but not yet in the send window.
+    pub send_queue: usize,
+    /// Segments sent but not yet acknowledged.
+    pub send_buf: usize,
+    /// Segments received but not yet consumed by the application.
+    pub recv_queue: usize,
+    /// Segments received out of order, waiting for the ones before them.
+    pub recv_buf: usize,
+    pub state: State,
+    pub dead_link: bool,
 }
 
 /// KCP Data Segment
@@ -155,6 +362,8 @@ impl Config {
 #[rustfmt::skip]
 struct Segment {
     frg: u8, ts: u32, sn: u32,
+    /// Whether this is a FIN instead of a PUSH.
+    fin: bool,
     /// Retransmission Timeout
     rto: u32,
     /// Number of times the packet is skip-ACKed.
@@ -181,6 +390,8 @@ pub struct ControlBlock {
     config: Config,
     /// If the underlying link is dead
     dead_link: bool,
+    /// Closing state of the connection.
+    state: State,
     /// Oldest Unacknowledged Packet in the send window.
     send_una: u32,
     /// Sequence number of the next packet to be sent.
@@ -207,12 +418,22 @@ pub struct ControlBlock {
     probe_tell: bool,
     /// Probing timeout.
     probe_timeout: u32,
+    /// Timestamp of the last packet from the other side.
+    ts_last_recv: u32,
+    /// Timestamp for next keepalive probe.
+    ts_keepalive: u32,
+    /// Timestamp of the last data sent or received by the application, or of the last window
+    /// probe from the other side.
+    ts_last_data: u32,
     /// Send queue, which stores packets that are enqueued but not in the send window.
     #[derivative(Debug = "ignore")]
     send_queue: VecDeque<Segment>,
     /// Receive queue, which stores packets that are received but not consumed by the application.
     #[derivative(Debug = "ignore")]
     recv_queue: VecDeque<Segment>,
+    /// Datagrams that are received but not consumed by the application.
+    #[derivative(Debug = "ignore")]
+    datagrams: VecDeque<Vec<u8>>,
     /// Send buffer, which stores packets sent but not yet acknowledged.
     #[derivative(Debug = "ignore")]
     send_buf: Window<Segment>,
@@ -229,14 +450,18 @@ pub struct ControlBlock {
     /// Buffer used to merge small packets into a batch (thus making better use of bandwidth).
     #[derivative(Debug = "ignore")]
     buffer: Vec<u8>,
-    /// The instant of the creation of the control block
-    #[derivative(Debug = "ignore")]
-    epoch: Instant,
     #[derivative(Debug = "ignore")]
     acks: VecDeque<(u32, u32)>,
 
     inflight: usize,
-    pcc: Option<PCC>,
+    pcc: Option<Pcc>,
+
+    /// Cumulative counters reported by [stats](ControlBlock::stats).
+    segments_sent: u64,
+    segments_resent: u64,
+    segments_fast_resent: u64,
+    bytes_acked: u64,
+    bytes_received: u64,
 }
 
 /// Actually Segment will not be sent between threads
@@ -267,6 +492,7 @@ impl ControlBlock {
         ControlBlock {
             conv,
             dead_link: false,
+            state: State::Established,
             send_una: 0,
             send_nxt: 0,
             recv_nxt: 0,
@@ -280,20 +506,28 @@ impl ControlBlock {
             probe_ask: false,
             probe_tell: false,
             probe_timeout: 0,
+            ts_last_recv: 0,
+            ts_keepalive: 0,
+            ts_last_data: 0,
             send_queue: Default::default(),
             recv_queue: Default::default(),
+            datagrams: Default::default(),
             send_buf: Window::with_size(config.send_wnd as usize),
             recv_buf: Window::with_size(config.recv_wnd as usize),
             timer: Timer::with_capacity(config.send_wnd as usize),
             output: Default::default(),
             buffer: Vec::with_capacity(config.mtu as usize),
-            epoch: Instant::now(),
             acks: Default::default(),
             inflight: 0,
             pcc: config
                 .pcc
                 .as_ref()
-                .map(|conf| PCC::new(conf.clone(), 0, config.rto_default)),
+                .map(|conf| Pcc::new(conf.clone(), 0, config.rto_default)),
+            segments_sent: 0,
+            segments_resent: 0,
+            segments_fast_resent: 0,
+            bytes_acked: 0,
+            bytes_received: 0,
 
             config,
         }
@@ -325,8 +559,24 @@ impl ControlBlock {
     /// **Note**: if [stream mode](#structfield.stream) is off (by default), then one receive
     /// corresponds to one [send](#method.send) on the other side. Otherwise, this correlation
     /// may not hold as in stream mode KCP will try to merge payloads to reduce overheads.
-    #[instrument(skip(self))]
+    ///
+    /// Returns [Closed](Error::Closed) once everything before the other side's FIN has been
+    /// received, or once the connection has been reset.
+    #[cfg_attr(feature = "tracing", instrument(skip(self)))]
     pub fn recv(&mut self) -> Result<Vec<u8>> {
+        if self.state == State::Reset {
+            return Err(Error::Closed);
+        }
+        if matches!(self.recv_queue.front(), Some(seg) if seg.fin) {
+            self.recv_queue.pop_front();
+            self.state = match self.state {
+                State::FinWait => State::Closed,
+                _ => State::CloseWait,
+            };
+        }
+        if matches!(self.state, State::CloseWait | State::Closed) {
+            return Err(Error::Closed);
+        }
         let size = self.peek_size()?;
         let mut ret = Vec::with_capacity(size);
         while !self.recv_queue.is_empty() {
@@ -337,6 +587,7 @@ impl ControlBlock {
             }
         }
         assert_eq!(size, ret.len());
+        self.bytes_received += size as u64;
         Ok(ret)
     }
 
@@ -348,8 +599,12 @@ impl ControlBlock {
     ///
     /// **Note**: After calling this do remember to call [check](#method.check), as
     /// an input packet may invalidate previous time estimations of the next update.
-    #[instrument(skip(self, buf), fields(len = buf.len()))]
+    #[cfg_attr(feature = "tracing", instrument(skip(self, buf), fields(len = buf.len())))]
     pub fn send(&mut self, mut buf: &[u8]) -> Result<()> {
+        if matches!(self.state, State::FinWait | State::Closed | State::Reset) {
+            return Err(Error::Closed);
+        }
+        self.ts_last_data = self.now;
         let mss = self.config.mss();
         if self.config.stream {
             if let Some(old) = self.send_queue.back_mut() {
@@ -369,7 +624,7 @@ impl ControlBlock {
         let count = if buf.len() <= mss {
             1
         } else {
-            (buf.len() + mss - 1) / mss
+            buf.len().div_ceil(mss)
         };
         if count > MAX_FRAGMENTS as usize {
             return Err(Error::OversizePacket);
@@ -389,11 +644,63 @@ impl ControlBlock {
             });
             buf = back;
         }
-        self.sync_now();
         self.flush_push();
         Ok(())
     }
 
+    /// Ends our sending direction with a FIN, which the other side receives after everything sent
+    /// before. Does nothing if the direction is already finished.
+    pub fn shutdown(&mut self) {
+        self.state = match self.state {
+            State::Established => State::FinWait,
+            State::CloseWait => State::Closed,
+            _ => return,
+        };
+        self.send_queue.push_back(Segment {
+            fin: true,
+            ..Default::default()
+        });
+        self.flush_push();
+    }
+
+    /// Aborts the connection with a RST, discarding everything not yet sent or received.
+    pub fn reset(&mut self) {
+        if self.state == State::Reset {
+            return;
+        }
+        self.state = State::Reset;
+        self.send_queue.clear();
+        self.recv_queue.clear();
+        self.flush_segment(Command::Rst, 0, 0, self.now, 0);
+    }
+
+    /// Sends a datagram, which is delivered at most once and never retransmitted.
+    ///
+    /// Datagrams skip the send and receive windows, but are still subject to congestion control:
+    /// they are dropped (with [Congested](Error::Congested)) while there is already too much data in
+    /// flight. A datagram must fit in a single segment.
+    #[cfg_attr(feature = "tracing", instrument(skip(self, buf), fields(len = buf.len())))]
+    pub fn send_datagram(&mut self, buf: &[u8]) -> Result<()> {
+        if buf.len() > self.config.mss() {
+            return Err(Error::OversizePacket);
+        }
+        if self.state == State::Reset {
+            return Err(Error::Closed);
+        }
+        if self.inflight > self.calc_inflight_limit() {
+            return Err(Error::Congested);
+        }
+        self.ts_last_data = self.now;
+        self.flush_segment(Command::Datagram, 0, 0, self.now, buf.len());
+        self.buffer.extend_from_slice(buf);
+        Ok(())
+    }
+
+    /// Receives a datagram sent by [send_datagram](#method.send_datagram) on the other side.
+    pub fn recv_datagram(&mut self) -> Result<Vec<u8>> {
+        self.datagrams.pop_front().ok_or(Error::NotAvailable)
+    }
+
     /// Updates the RTT filter and recalculates RTO according to RFC 6298.
     fn update_rtt_filters(&mut self, rtt: u32) {
         if self.srtt == 0 {
@@ -419,10 +726,11 @@ impl ControlBlock {
         self.inflight = self
             .inflight
             .saturating_sub(seg.payload.len() + OVERHEAD as usize);
+        self.bytes_acked += seg.payload.len() as u64;
         let rtt = max(self.now - seg.ts_last_send, 1);
         self.update_rtt_filters(rtt);
         if let Some(pcc) = &mut self.pcc {
-            pcc.on_ack(&seg);
+            pcc.on_ack(seg);
             pcc.update(self.now, self.srtt);
         }
     }
@@ -458,8 +766,8 @@ impl ControlBlock {
             let now = self.now;
             self.send_buf.for_preceding(sn as usize, |seg| {
                 seg.skip_acks += 1;
-                if fast_resend_thres.map_or(false, |thres| seg.skip_acks == thres)
-                    && fast_resend_limit.map_or(true, |limit| seg.sends <= limit)
+                if fast_resend_thres == Some(seg.skip_acks)
+                    && fast_resend_limit.is_none_or(|limit| seg.sends <= limit)
                 {
                     seg.ts = now;
                     timer.schedule(now, seg.sn);
@@ -490,9 +798,8 @@ impl ControlBlock {
     ///
     /// **Note**: After calling this do remember to call [check](#method.check), as
     /// an input packet may invalidate previous time estimations of the next update.
-    #[instrument(skip(self, data), fields(len = data.len()))]
+    #[cfg_attr(feature = "tracing", instrument(skip(self, data), fields(len = data.len())))]
     pub fn input(&mut self, mut data: &[u8]) -> Result<usize> {
-        self.sync_now();
         let prev_len = data.len();
         let mut sn_max_ack = None;
         if data.len() < OVERHEAD as usize {
@@ -520,6 +827,7 @@ impl ControlBlock {
                 return Err(Error::IncompletePacket);
             }
             let cmd = Command::try_from_primitive(cmd).map_err(|_| Error::InvalidCommand(cmd))?;
+            self.ts_last_recv = self.now;
             self.rmt_wnd = wnd;
             self.ack_packets_before_una(una);
             self.update_una();
@@ -529,21 +837,40 @@ impl ControlBlock {
                     self.update_una();
                     sn_max_ack = Some(max(sn, sn_max_ack.unwrap_or_default()));
                 }
-                Command::Push => {
+                Command::Push | Command::Fin => {
+                    self.ts_last_data = self.now;
                     if sn < self.recv_nxt + self.config.recv_wnd as u32 {
                         self.acks.push_back((sn, ts));
                         if sn >= self.recv_nxt {
                             self.push_segment(Segment {
                                 sn,
                                 frg,
+                                fin: matches!(cmd, Command::Fin),
                                 payload: data[..len].into(),
                                 ..Default::default()
                             });
                         }
                     }
                 }
-                Command::AskWnd => self.probe_tell = true,
-                Command::TellWnd => {}
+                Command::Rst => {
+                    self.state = State::Reset;
+                    self.send_queue.clear();
+                    self.recv_queue.clear();
+                }
+                // Window probes count as activity, so that keepalive probes and their answers keep
+                // an idle connection from timing out
+                Command::AskWnd => {
+                    self.ts_last_data = self.now;
+                    self.probe_tell = true;
+                }
+                Command::TellWnd => self.ts_last_data = self.now,
+                Command::Datagram => {
+                    self.ts_last_data = self.now;
+                    // Datagrams are best effort, so just drop them if the application lags behind
+                    if self.datagrams.len() < self.config.recv_wnd as usize {
+                        self.datagrams.push_back(data[..len].into());
+                    }
+                }
             }
             data = &data[len..];
         }
@@ -603,9 +930,24 @@ impl ControlBlock {
         self.buffer.put_u32_le(len as u32);
     }
 
+    /// Probes the other side if we have not heard from it for a while, or marks the link as dead
+    /// if that has been going on for too long.
+    fn update_keepalive(&mut self) {
+        if let Some(keepalive) = self.config.keepalive {
+            let silence = self.now.saturating_sub(self.ts_last_recv);
+            if silence >= self.config.keepalive_timeout {
+                self.dead_link = true;
+            } else if silence >= keepalive && self.now >= self.ts_keepalive {
+                self.probe_ask = true;
+                self.ts_keepalive = self.now + keepalive;
+            }
+        }
+    }
+
     /// Flush all window-probing-related segments
     fn flush_probe(&mut self) {
         self.update_probe();
+        self.update_keepalive();
         if self.probe_ask {
             self.flush_segment(Command::AskWnd, 0, 0, 0, 0);
             self.probe_ask = false;
@@ -626,17 +968,18 @@ impl ControlBlock {
             pcc.update(self.now, self.srtt);
             (pcc.rate() * self.srtt as f64).round() as usize
         } else {
-            usize::max_value()
+            usize::MAX
         }
     }
 
     /// Prepare a segment for (re)transmission
     #[rustfmt::skip]
-    fn prepare_send(&self, seg: &mut Segment) -> u32 {
+    fn prepare_send(&mut self, seg: &mut Segment) -> u32 {
         seg.sends += 1;
         seg.ts = self.now;
         // First retransmission
         if seg.sends == 1 {
+            self.segments_sent += 1;
             seg.rto = self.rto;
             seg.skip_acks = 0;
             if self.config.nodelay {
@@ -645,15 +988,17 @@ impl ControlBlock {
                 self.now + seg.rto + self.config.rto_min
             }
         } else if self.config.fast_resend_thres
-            .map_or(false, |thres| seg.skip_acks >= thres)
+            .is_some_and(|thres| seg.skip_acks >= thres)
             && self.config.fast_resend_limit
-                .map_or(true, |limit| seg.sends <= limit)
+                .is_none_or(|limit| seg.sends <= limit)
         {
             // Fast retransmission
+            self.segments_fast_resent += 1;
             seg.skip_acks = 0;
             self.now + seg.rto
         } else {
             // Regular retransmission
+            self.segments_resent += 1;
             seg.rto = if self.config.nodelay {
                 max(seg.rto, self.rto)
             } else {
@@ -700,7 +1045,8 @@ impl ControlBlock {
                     seg.ts = self.prepare_send(seg);
                     seg.ts_last_send = ts;
                     self.dead_link |= seg.sends >= self.config.dead_link_thres;
-                    self.flush_segment(Command::Push, seg.frg, seg.sn, ts, seg.payload.len());
+                    let cmd = if seg.fin { Command::Fin } else { Command::Push };
+                    self.flush_segment(cmd, seg.frg, seg.sn, ts, seg.payload.len());
                     self.buffer.extend_from_slice(&seg.payload);
                     self.timer.schedule(seg.ts, seg.sn);
                 }
@@ -718,9 +1064,8 @@ impl ControlBlock {
     /// Flushes packets from the [send queue](#structfield.send_queue) to the
     /// [send buffer](#structfield.send_buf), and (re)transmits the packets in the send buffer
     /// if necessary.
-    #[instrument(skip(self))]
+    #[cfg_attr(feature = "tracing", instrument(skip(self)))]
     pub fn flush(&mut self) {
-        self.sync_now();
         self.flush_probe();
         self.flush_push();
         self.flush_ack();
@@ -731,8 +1076,11 @@ impl ControlBlock {
         }
     }
 
-    fn sync_now(&mut self) {
-        self.now = self.epoch.elapsed().as_millis() as u32;
+    /// Sets the current time (ms), which [send](#method.send), [input](#method.input),
+    /// [flush](#method.flush) etc. work with until the next update. Any clock will do as long as
+    /// it is monotonic and starts near 0, as with `current` in `ikcp_update`.
+    pub fn update(&mut self, current: u32) {
+        self.now = current;
     }
 
     /// Gets the number of packets wait to be sent. This includes both unsent packets and packets
@@ -753,6 +1101,16 @@ impl ControlBlock {
         self.dead_link
     }
 
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    /// Time (ms) since the application last sent or received any data, or since the other side
+    /// last probed our window or answered a probe of its own, as of the last update.
+    pub fn idle(&self) -> u32 {
+        self.now.saturating_sub(self.ts_last_data)
+    }
+
     pub fn conv(&self) -> u32 {
         self.conv
     }
@@ -761,6 +1119,36 @@ impl ControlBlock {
         &self.config
     }
 
+    /// Takes a snapshot of the counters, RTT estimates, windows and queues.
+    pub fn stats(&self) -> Stats {
+        let resent = self.segments_resent + self.segments_fast_resent;
+        Stats {
+            segments_sent: self.segments_sent,
+            segments_resent: self.segments_resent,
+            segments_fast_resent: self.segments_fast_resent,
+            bytes_acked: self.bytes_acked,
+            bytes_received: self.bytes_received,
+            retransmit_ratio: if self.segments_sent > 0 {
+                resent as f64 / self.segments_sent as f64
+            } else {
+                0.0
+            },
+            send_rate: self.pcc.as_ref().map(|pcc| pcc.rate()),
+            pcc_phase: self.pcc.as_ref().map(|pcc| pcc.phase()),
+            srtt: self.srtt,
+            rtt_var: self.rtt_var,
+            rto: self.rto,
+            inflight: self.inflight,
+            rmt_wnd: self.rmt_wnd,
+            send_queue: self.send_queue.len(),
+            send_buf: self.send_buf.len(),
+            recv_queue: self.recv_queue.len(),
+            recv_buf: self.recv_buf.len(),
+            state: self.state,
+            dead_link: self.dead_link,
+        }
+    }
+
     pub fn debug(&self) {
         if let Some(pcc) = &self.pcc {
             pcc.debug();
@@ -769,11 +1157,7 @@ impl ControlBlock {
 }
 
 fn diff(x: u32, y: u32) -> u32 {
-    if x >= y {
-        x - y
-    } else {
-        y - x
-    }
+    x.abs_diff(y)
 }
 
 /// Gets the conversation id from a raw buffer.
@@ -784,8 +1168,12 @@ pub fn conv_from_raw(buf: &[u8]) -> u32 {
 }
 
 /// Check if the given raw buffer `buf` contains the first PUSH packet, which marks the start
-/// of a new connection.
+/// of a new connection. Packets without any PUSH segment (stray ACKs, window probes, datagrams)
+/// never start one.
 pub fn first_push_packet(mut buf: &[u8]) -> bool {
+    if contains_command(buf, Command::Fin) || contains_command(buf, Command::Rst) {
+        return false;
+    }
     while buf.len() >= OVERHEAD as usize {
         let _conv = buf.get_u32_le();
         let cmd = buf.get_u8();
@@ -798,7 +1186,306 @@ pub fn first_push_packet(mut buf: &[u8]) -> bool {
         if cmd == Command::Push as u8 {
             return sn == 0;
         }
+        if len > buf.len() {
+            break;
+        }
         buf = &buf[len..];
     }
-    true
+    false
+}
+
+fn contains_command(mut buf: &[u8], command: Command) -> bool {
+    while buf.len() >= OVERHEAD as usize {
+        let _conv = buf.get_u32_le();
+        let cmd = buf.get_u8();
+        buf.advance(15);
+        let len = buf.get_u32_le() as usize;
+        if cmd == command as u8 {
+            return true;
+        }
+        buf = &buf[min(len, buf.len())..];
+    }
+    false
+}
+
+/// Builds the RST answering a raw buffer `buf` that belongs to no known connection, unless `buf`
+/// is a RST itself.
+pub fn reset_reply(buf: &[u8]) -> Option<Vec<u8>> {
+    if buf.len() < OVERHEAD as usize || contains_command(buf, Command::Rst) {
+        return None;
+    }
+    let mut ret = Vec::with_capacity(OVERHEAD as usize);
+    ret.put_u32_le(conv_from_raw(buf));
+    ret.put_u8(Command::Rst.into());
+    ret.put_u8(0);
+    ret.put_u16_le(0);
+    ret.put_u32_le(0);
+    ret.put_u32_le(0);
+    ret.put_u32_le(0);
+    ret.put_u32_le(0);
+    Some(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn segment(cmd: Command, sn: u32, data: &[u8]) -> Vec<u8> {
+        let mut ret = Vec::with_capacity(OVERHEAD as usize + data.len());
+        ret.put_u32_le(42);
+        ret.put_u8(cmd.into());
+        ret.put_u8(0);
+        ret.put_u16_le(128);
+        ret.put_u32_le(0);
+        ret.put_u32_le(sn);
+        ret.put_u32_le(0);
+        ret.put_u32_le(data.len() as u32);
+        ret.extend_from_slice(data);
+        ret
+    }
+
+    /// The commands of the segments in a raw packet.
+    fn commands(mut packet: &[u8]) -> Vec<u8> {
+        let mut ret = Vec::new();
+        while packet.len() >= OVERHEAD as usize {
+            ret.push(packet[4]);
+            let len = u32::from_le_bytes(packet[20..24].try_into().unwrap()) as usize;
+            packet = &packet[OVERHEAD as usize + len..];
+        }
+        ret
+    }
+
+    /// Flushes `from` and feeds everything it outputs into `to`.
+    fn deliver(from: &mut ControlBlock, to: &mut ControlBlock) {
+        from.flush();
+        while let Some(packet) = from.output() {
+            to.input(&packet).unwrap();
+        }
+    }
+
+    fn pair(config: Config) -> (ControlBlock, ControlBlock) {
+        (
+            ControlBlock::new(42, config.clone()),
+            ControlBlock::new(42, config),
+        )
+    }
+
+    #[test]
+    fn first_push() {
+        assert!(first_push_packet(&segment(Command::Push, 0, b"hello")));
+        let mut packet = segment(Command::AskWnd, 0, b"");
+        packet.extend(segment(Command::Push, 0, b"hello"));
+        assert!(first_push_packet(&packet));
+    }
+
+    #[test]
+    fn not_first_push() {
+        assert!(!first_push_packet(&[]));
+        assert!(!first_push_packet(&segment(Command::Push, 1, b"hello")));
+        assert!(!first_push_packet(&segment(Command::Ack, 0, b"")));
+        assert!(!first_push_packet(&segment(Command::AskWnd, 0, b"")));
+        assert!(!first_push_packet(&segment(Command::TellWnd, 0, b"")));
+        assert!(!first_push_packet(&segment(Command::Datagram, 0, b"hello")));
+        assert!(!first_push_packet(&segment(Command::Fin, 0, b"")));
+
+        let mut packet = segment(Command::Push, 0, b"hello");
+        packet.extend(segment(Command::Rst, 0, b""));
+        assert!(!first_push_packet(&packet));
+
+        // A segment claiming more data than the packet holds
+        let mut packet = segment(Command::Ack, 0, b"");
+        packet[20..24].copy_from_slice(&1000u32.to_le_bytes());
+        packet.extend(segment(Command::Push, 0, b"hello"));
+        assert!(!first_push_packet(&packet));
+    }
+
+    #[test]
+    fn datagram_oversize() {
+        let mut kcp = ControlBlock::new(42, Config::default());
+        let mss = kcp.config().mss();
+        assert!(matches!(
+            kcp.send_datagram(&vec![0; mss + 1]),
+            Err(Error::OversizePacket)
+        ));
+        kcp.send_datagram(&vec![0; mss]).unwrap();
+    }
+
+    #[test]
+    fn datagram_congested() {
+        let mut kcp = ControlBlock::new(
+            42,
+            Config {
+                pcc: Some(Default::default()),
+                ..Default::default()
+            },
+        );
+        kcp.inflight = usize::MAX;
+        assert!(matches!(kcp.send_datagram(b"hello"), Err(Error::Congested)));
+    }
+
+    #[test]
+    fn datagram_with_push() {
+        let (mut a, mut b) = pair(Config::default());
+        a.send(b"reliable").unwrap();
+        a.send_datagram(b"unreliable").unwrap();
+        a.flush();
+        let packet = a.output().unwrap();
+        assert!(a.output().is_none());
+        assert_eq!(
+            commands(&packet),
+            [Command::Push as u8, Command::Datagram as u8]
+        );
+        b.input(&packet).unwrap();
+        assert_eq!(b.recv().unwrap(), b"reliable");
+        assert_eq!(b.recv_datagram().unwrap(), b"unreliable");
+        assert!(matches!(b.recv_datagram(), Err(Error::NotAvailable)));
+    }
+
+    #[test]
+    fn datagram_not_retransmitted() {
+        let (mut a, mut b) = pair(Config::default());
+        a.send(b"reliable").unwrap();
+        a.send_datagram(b"unreliable").unwrap();
+        a.flush();
+        // Lost
+        assert!(a.output().is_some());
+        a.update(10_000);
+        a.flush();
+        let packet = a.output().unwrap();
+        assert_eq!(commands(&packet), [Command::Push as u8]);
+        b.input(&packet).unwrap();
+        assert_eq!(b.recv().unwrap(), b"reliable");
+        assert!(matches!(b.recv_datagram(), Err(Error::NotAvailable)));
+    }
+
+    #[test]
+    fn datagram_queue_cap() {
+        let (mut a, mut b) = pair(Config {
+            recv_wnd: 4,
+            ..Default::default()
+        });
+        for i in 0..6u8 {
+            a.send_datagram(&[i]).unwrap();
+        }
+        deliver(&mut a, &mut b);
+        // Those beyond the receive window are dropped
+        for i in 0..4u8 {
+            assert_eq!(b.recv_datagram().unwrap(), [i]);
+        }
+        assert!(matches!(b.recv_datagram(), Err(Error::NotAvailable)));
+        // Nor are datagrams acknowledged
+        b.flush();
+        assert!(b.output().is_none());
+    }
+
+    #[test]
+    fn keepalive_is_activity() {
+        let (mut a, mut b) = pair(Config {
+            keepalive: Some(1000),
+            ..Default::default()
+        });
+        a.update(5000);
+        b.update(5000);
+        assert_eq!(a.idle(), 5000);
+        assert_eq!(b.idle(), 5000);
+        // The probe keeps b from timing out, and its answer a
+        deliver(&mut a, &mut b);
+        assert_eq!(b.idle(), 0);
+        deliver(&mut b, &mut a);
+        assert_eq!(a.idle(), 0);
+    }
+
+    #[test]
+    fn close() {
+        let (mut a, mut b) = pair(Config::default());
+        a.send(b"hello").unwrap();
+        a.shutdown();
+        a.shutdown();
+        assert_eq!(a.state(), State::FinWait);
+        assert_eq!(a.wait_send(), 2);
+        assert!(matches!(a.send(b"more"), Err(Error::Closed)));
+
+        deliver(&mut a, &mut b);
+        assert_eq!(b.recv().unwrap(), b"hello");
+        assert!(matches!(b.recv(), Err(Error::Closed)));
+        assert_eq!(b.state(), State::CloseWait);
+        // The other direction is still open
+        b.send(b"bye").unwrap();
+        b.shutdown();
+        assert_eq!(b.state(), State::Closed);
+        assert!(matches!(b.send(b"more"), Err(Error::Closed)));
+
+        deliver(&mut b, &mut a);
+        assert!(a.all_flushed());
+        assert_eq!(a.recv().unwrap(), b"bye");
+        assert!(matches!(a.recv(), Err(Error::Closed)));
+        assert_eq!(a.state(), State::Closed);
+        deliver(&mut a, &mut b);
+        assert!(b.all_flushed());
+    }
+
+    #[test]
+    fn simultaneous_close() {
+        let (mut a, mut b) = pair(Config::default());
+        a.shutdown();
+        b.shutdown();
+        deliver(&mut a, &mut b);
+        deliver(&mut b, &mut a);
+        deliver(&mut a, &mut b);
+        for kcp in [&mut a, &mut b] {
+            assert_eq!(kcp.state(), State::FinWait);
+            assert!(matches!(kcp.recv(), Err(Error::Closed)));
+            assert_eq!(kcp.state(), State::Closed);
+            assert!(kcp.all_flushed());
+        }
+    }
+
+    #[test]
+    fn reset() {
+        let (mut a, mut b) = pair(Config::default());
+        a.send(b"hello").unwrap();
+        a.reset();
+        assert_eq!(a.state(), State::Reset);
+        assert!(matches!(a.send(b"more"), Err(Error::Closed)));
+        assert!(matches!(a.send_datagram(b"more"), Err(Error::Closed)));
+        assert!(matches!(a.recv(), Err(Error::Closed)));
+
+        // Whatever arrived along with the RST is discarded
+        deliver(&mut a, &mut b);
+        assert_eq!(b.state(), State::Reset);
+        assert!(matches!(b.recv(), Err(Error::Closed)));
+        assert!(matches!(b.send(b"more"), Err(Error::Closed)));
+        // Closing a reset connection does not revive it
+        b.shutdown();
+        assert_eq!(b.state(), State::Reset);
+    }
+
+    #[test]
+    fn reset_after_fin() {
+        let (mut a, mut b) = pair(Config::default());
+        a.shutdown();
+        deliver(&mut a, &mut b);
+        assert!(matches!(b.recv(), Err(Error::Closed)));
+        b.reset();
+        deliver(&mut b, &mut a);
+        assert_eq!(a.state(), State::Reset);
+        assert!(matches!(a.recv(), Err(Error::Closed)));
+    }
+
+    #[test]
+    fn reset_unknown_conv() {
+        let reply = reset_reply(&segment(Command::Push, 0, b"hello")).unwrap();
+        assert_eq!(commands(&reply), [Command::Rst as u8]);
+        assert_eq!(conv_from_raw(&reply), 42);
+        let mut kcp = ControlBlock::new(42, Config::default());
+        kcp.input(&reply).unwrap();
+        assert_eq!(kcp.state(), State::Reset);
+
+        // RSTs are never answered, lest two ends keep resetting each other
+        assert!(reset_reply(&segment(Command::Rst, 0, b"")).is_none());
+        let mut packet = segment(Command::Ack, 0, b"");
+        packet.extend(segment(Command::Rst, 0, b""));
+        assert!(reset_reply(&packet).is_none());
+        assert!(reset_reply(&[42, 0, 0, 0]).is_none());
+    }
 }
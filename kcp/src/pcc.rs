@@ -1,16 +1,17 @@
-use super::{Segment, OVERHEAD};
+use super::{ConfigError, Segment, OVERHEAD};
 use derivative::Derivative;
 use rand::seq::SliceRandom;
 use rand::{thread_rng, Rng};
-use serde::Deserialize;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
 use std::cell::RefCell;
 use std::rc::Rc;
 use tinyvec::{array_vec, ArrayVec};
-use tracing::debug;
 
-#[derive(Clone, Debug, Deserialize, Derivative)]
+/// Configuration of PCC congestion control.
+#[derive(Clone, Debug, Derivative)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize), serde(default))]
 #[derivative(Default)]
-#[serde(default)]
 pub struct Config {
     #[derivative(Default(value = "16.0"))]
     startup_rate: f64,
@@ -28,6 +29,50 @@ pub struct Config {
     mi_min_sends: usize,
 }
 
+impl Config {
+    /// Checks the configuration for values PCC cannot work with, and returns every problem found.
+    pub fn validate(&self) -> Vec<ConfigError> {
+        let mut errors = Vec::new();
+        let mut check = |ok: bool, field: &str, message: String| {
+            if !ok {
+                errors.push(ConfigError::new(field, message));
+            }
+        };
+        check(
+            self.startup_rate > 0.0,
+            "startup_rate",
+            "must be positive".into(),
+        );
+        check(
+            self.startup_rate <= self.max_rate,
+            "max_rate",
+            format!("must be at least startup_rate ({})", self.startup_rate),
+        );
+        check(self.eps_min > 0.0, "eps_min", "must be positive".into());
+        check(
+            self.eps_min <= self.eps_max,
+            "eps_max",
+            format!("must be at least eps_min ({})", self.eps_min),
+        );
+        check(
+            (0.0..1.0).contains(&self.loss_tol),
+            "loss_tol",
+            "must be in [0, 1)".into(),
+        );
+        check(
+            self.loss_coeff >= 0.0,
+            "loss_coeff",
+            "must not be negative".into(),
+        );
+        check(
+            self.mi_min_sends > 0,
+            "mi_min_sends",
+            "must be positive".into(),
+        );
+        errors
+    }
+}
+
 #[derive(Default, Debug)]
 pub(super) struct MonitorInterval {
     rate: f64,
@@ -79,7 +124,7 @@ enum State {
 }
 
 #[derive(Debug)]
-pub(super) struct PCC {
+pub(super) struct Pcc {
     state: State,
     config: Config,
     mi_now: Rc<RefCell<MonitorInterval>>,
@@ -102,9 +147,9 @@ impl State {
     }
 }
 
-impl PCC {
+impl Pcc {
     pub(super) fn new(config: Config, now: u32, rtt: u32) -> Self {
-        PCC {
+        Pcc {
             state: State::Starting {
                 rate: config.startup_rate,
                 optimal: None,
@@ -265,6 +310,15 @@ impl PCC {
         self.mi_now.borrow().rate
     }
 
+    /// The phase PCC is in.
+    pub(super) fn phase(&self) -> &'static str {
+        match self.state {
+            State::Starting { .. } => "starting",
+            State::DecisionMaking { .. } => "decision_making",
+            State::RateAdjusting { .. } => "rate_adjusting",
+        }
+    }
+
     pub(super) fn debug(&self) {
         debug!("{}kBps @ {:?}", self.rate(), self.state);
     }
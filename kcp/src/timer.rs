@@ -35,7 +35,7 @@ impl Timer {
     pub fn imminent(&self) -> u32 {
         match self.0.peek() {
             Some(&Reverse(val)) => (val >> 32) as u32,
-            None => u32::max_value(),
+            None => u32::MAX,
         }
     }
 
@@ -43,7 +43,7 @@ impl Timer {
         let key = (now as u64 + 1) << 32;
         match self.0.peek() {
             Some(&Reverse(val)) if val < key => {
-                let sn = val & (u32::max_value() as u64);
+                let sn = val & (u32::MAX as u64);
                 let ts = val >> 32;
                 self.0.pop();
                 Some((ts as u32, sn as u32))
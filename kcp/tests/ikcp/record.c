@@ -0,0 +1,104 @@
+/*
+ * Records the packets the C reference implementation of KCP (ikcp.c) emits in the scenarios of
+ * tests/ikcp_recorded.rs, one per line as "<scenario> <hex>". See record.sh.
+ *
+ * Every control block is set up like ekho_kcp's default Config: 1024-segment windows, a 40 ms
+ * interval, no nodelay and no fast resend. Congestion control is off (nc = 1), since ekho_kcp
+ * has its own.
+ */
+
+#include <stdio.h>
+#include <string.h>
+
+#include "ikcp.h"
+
+/* The packets from the last flush, to be fed to another control block */
+static char captured[8][2048];
+static int captured_len[8];
+static int captured_count;
+
+static int output(const char *buf, int len, ikcpcb *kcp, void *user)
+{
+	int i;
+	(void)kcp;
+	printf("%s ", (const char *)user);
+	for (i = 0; i < len; i++)
+		printf("%02x", (unsigned char)buf[i]);
+	printf("\n");
+	if (captured_count < 8 && len <= 2048) {
+		memcpy(captured[captured_count], buf, len);
+		captured_len[captured_count++] = len;
+	}
+	return 0;
+}
+
+static ikcpcb *create(IUINT32 conv, const char *scenario, int mtu)
+{
+	ikcpcb *kcp = ikcp_create(conv, (void *)scenario);
+	ikcp_setoutput(kcp, output);
+	ikcp_setmtu(kcp, mtu);
+	ikcp_wndsize(kcp, 1024, 1024);
+	ikcp_nodelay(kcp, 0, 40, 0, 1);
+	return kcp;
+}
+
+static void push(void)
+{
+	ikcpcb *kcp = create(0x01020304, "push", 536);
+	ikcp_update(kcp, 100);
+	ikcp_send(kcp, "hello", 5);
+	ikcp_flush(kcp);
+	ikcp_release(kcp);
+}
+
+static void fragmentation(void)
+{
+	char message[100];
+	int i;
+	ikcpcb *kcp = create(7, "fragmentation", 64);
+	for (i = 0; i < 100; i++)
+		message[i] = (char)i;
+	ikcp_update(kcp, 1000);
+	ikcp_send(kcp, message, 100);
+	ikcp_flush(kcp);
+	ikcp_release(kcp);
+}
+
+static void ack(void)
+{
+	int i, count;
+	ikcpcb *sender = create(0xdeadbeef, "ack.in", 536);
+	ikcpcb *receiver = create(0xdeadbeef, "ack.out", 536);
+	ikcp_update(sender, 5000);
+	ikcp_send(sender, "foo", 3);
+	ikcp_send(sender, "bar", 3);
+	captured_count = 0;
+	ikcp_flush(sender);
+	count = captured_count;
+	ikcp_update(receiver, 20);
+	for (i = 0; i < count; i++)
+		ikcp_input(receiver, captured[i], captured_len[i]);
+	ikcp_flush(receiver);
+	ikcp_release(sender);
+	ikcp_release(receiver);
+}
+
+static void window(void)
+{
+	/* A window probe (WASK) from the other side */
+	static const char wask[24] = { 9, 0, 0, 0, 83, 0, (char)0x80, 0 };
+	ikcpcb *kcp = create(9, "window", 536);
+	ikcp_update(kcp, 0);
+	ikcp_input(kcp, wask, sizeof(wask));
+	ikcp_flush(kcp);
+	ikcp_release(kcp);
+}
+
+int main(void)
+{
+	push();
+	fragmentation();
+	ack();
+	window();
+	return 0;
+}
@@ -0,0 +1,11 @@
+#!/bin/sh
+# Records vectors.txt from the C reference implementation of KCP, given a checkout of
+# https://github.com/skywind3000/kcp:
+#
+#   kcp/tests/ikcp/record.sh path/to/kcp
+set -e
+dir=$(dirname "$0")
+bin=$(mktemp)
+trap 'rm -f "$bin"' EXIT
+${CC:-cc} -O2 -I "$1" -o "$bin" "$dir/record.c" "$1/ikcp.c"
+"$bin" > "$dir/vectors.txt"
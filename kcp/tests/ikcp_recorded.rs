@@ -0,0 +1,115 @@
+/*
+Copyright 2021 Chengyuan Ma
+
+Permission is hereby granted, free of charge, to any person obtaining a copy of this software and
+associated documentation files (the "Software"), to deal in the Software without restriction,
+including without limitation the rights to use, copy, modify, merge, publish, distribute, sub-
+-license, and/or sell copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all copies or substantial
+portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING BUT
+NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NON-
+-INFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES
+OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+*/
+
+//! Vectors recorded from the C reference implementation (`ikcp.c`).
+//!
+//! `ikcp/record.c` runs each scenario below against `ikcp.c` and writes what it sends to
+//! `ikcp/vectors.txt`, one packet per line as `<scenario> <hex>`. To record them again, run
+//! `ikcp/record.sh` with a checkout of <https://github.com/skywind3000/kcp>, then check in the
+//! result. Both sides are set up alike: the default [Config], with congestion control off on the
+//! C side.
+
+use ekho_kcp::{Config, ControlBlock};
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Recorded packets by scenario, in the order they were sent.
+fn vectors() -> HashMap<String, Vec<Vec<u8>>> {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/ikcp/vectors.txt");
+    let text = std::fs::read_to_string(&path)
+        .unwrap_or_else(|err| panic!("{}: {} (see ikcp/record.sh)", path.display(), err));
+    let mut ret: HashMap<_, Vec<_>> = HashMap::new();
+    for line in text.lines().filter(|line| !line.is_empty()) {
+        let (scenario, packet) = line.split_once(' ').expect("malformed vector");
+        ret.entry(scenario.into()).or_default().push(hex(packet));
+    }
+    ret
+}
+
+fn hex(s: &str) -> Vec<u8> {
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).expect("malformed vector"))
+        .collect()
+}
+
+fn outputs(kcp: &mut ControlBlock) -> Vec<Vec<u8>> {
+    std::iter::from_fn(|| kcp.output()).collect()
+}
+
+#[test]
+#[ignore = "needs ikcp/vectors.txt, recorded by ikcp/record.sh"]
+fn push() {
+    let mut kcp = ControlBlock::new(0x0102_0304, Config::default());
+    kcp.update(100);
+    kcp.send(b"hello").unwrap();
+    kcp.flush();
+    assert_eq!(outputs(&mut kcp), vectors()["push"]);
+}
+
+#[test]
+#[ignore = "needs ikcp/vectors.txt, recorded by ikcp/record.sh"]
+fn fragmentation() {
+    let config = Config {
+        mtu: 64,
+        ..Default::default()
+    };
+    let message: Vec<u8> = (0..100).collect();
+    let mut kcp = ControlBlock::new(7, config);
+    kcp.update(1000);
+    kcp.send(&message).unwrap();
+    kcp.flush();
+    assert_eq!(outputs(&mut kcp), vectors()["fragmentation"]);
+}
+
+#[test]
+#[ignore = "needs ikcp/vectors.txt, recorded by ikcp/record.sh"]
+fn ack() {
+    let vectors = vectors();
+    let mut sender = ControlBlock::new(0xdead_beef, Config::default());
+    sender.update(5000);
+    sender.send(b"foo").unwrap();
+    sender.send(b"bar").unwrap();
+    sender.flush();
+    assert_eq!(outputs(&mut sender), vectors["ack.in"]);
+
+    let mut receiver = ControlBlock::new(0xdead_beef, Config::default());
+    receiver.update(20);
+    for packet in &vectors["ack.in"] {
+        receiver.input(packet).unwrap();
+    }
+    receiver.flush();
+    assert_eq!(outputs(&mut receiver), vectors["ack.out"]);
+    assert_eq!(receiver.recv().unwrap(), b"foo");
+    assert_eq!(receiver.recv().unwrap(), b"bar");
+}
+
+#[test]
+#[ignore = "needs ikcp/vectors.txt, recorded by ikcp/record.sh"]
+fn window() {
+    let mut wask = vec![0; 24];
+    wask[0] = 9;
+    wask[4] = 83;
+    wask[6] = 0x80;
+    let mut kcp = ControlBlock::new(9, Config::default());
+    kcp.update(0);
+    kcp.input(&wask).unwrap();
+    kcp.flush();
+    assert_eq!(outputs(&mut kcp), vectors()["window"]);
+}
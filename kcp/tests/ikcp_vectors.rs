@@ -0,0 +1,336 @@
+/*
+Copyright 2021 Chengyuan Ma
+
+Permission is hereby granted, free of charge, to any person obtaining a copy of this software and
+associated documentation files (the "Software"), to deal in the Software without restriction,
+including without limitation the rights to use, copy, modify, merge, publish, distribute, sub-
+-license, and/or sell copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all copies or substantial
+portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING BUT
+NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NON-
+-INFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES
+OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+*/
+
+//! Wire-format vectors for interoperability with the C reference implementation (`ikcp.c`).
+//!
+//! The vectors follow `ikcp_encode_seg`: every segment starts with a 24-byte little-endian header
+//!
+//! ```text
+//! 0       4     5     6       8       12      16      20      24
+//! | conv  | cmd | frg | wnd   | ts    | sn    | una   | len   | data (len bytes)
+//! ```
+//!
+//! with `cmd` 81 (PUSH), 82 (ACK), 83 (WASK) or 84 (WINS), and segments are packed into packets of
+//! at most `mtu` bytes. Fragmentation follows `ikcp_send`: a message of `n` segments carries
+//! `frg = n - 1, ..., 0`, and stream mode sets `frg = 0` throughout. The packets fed to
+//! [ControlBlock::input] are what `ikcp_flush` emits for the same traffic.
+//!
+//! These vectors are written out by hand; `ikcp_recorded.rs` checks against packets recorded from
+//! `ikcp.c` itself.
+
+use ekho_kcp::{conv_from_raw, Config, ControlBlock, Error};
+use std::convert::TryInto;
+
+const PUSH: u8 = 81;
+const ACK: u8 = 82;
+const WASK: u8 = 83;
+const WINS: u8 = 84;
+
+/// A decoded segment header.
+#[derive(Debug, PartialEq, Eq)]
+struct Header {
+    conv: u32,
+    cmd: u8,
+    frg: u8,
+    wnd: u16,
+    ts: u32,
+    sn: u32,
+    una: u32,
+    len: u32,
+}
+
+impl Header {
+    fn parse(buf: &[u8]) -> Header {
+        Header {
+            conv: u32::from_le_bytes(buf[0..4].try_into().unwrap()),
+            cmd: buf[4],
+            frg: buf[5],
+            wnd: u16::from_le_bytes(buf[6..8].try_into().unwrap()),
+            ts: u32::from_le_bytes(buf[8..12].try_into().unwrap()),
+            sn: u32::from_le_bytes(buf[12..16].try_into().unwrap()),
+            una: u32::from_le_bytes(buf[16..20].try_into().unwrap()),
+            len: u32::from_le_bytes(buf[20..24].try_into().unwrap()),
+        }
+    }
+}
+
+/// Splits a packet into its segments.
+fn segments(mut packet: &[u8]) -> Vec<(Header, Vec<u8>)> {
+    let mut ret = Vec::new();
+    while !packet.is_empty() {
+        let header = Header::parse(packet);
+        let end = 24 + header.len as usize;
+        ret.push((header, packet[24..end].to_vec()));
+        packet = &packet[end..];
+    }
+    ret
+}
+
+fn outputs(kcp: &mut ControlBlock) -> Vec<Vec<u8>> {
+    std::iter::from_fn(|| kcp.output()).collect()
+}
+
+#[test]
+fn push_layout() {
+    let mut kcp = ControlBlock::new(0x0102_0304, Config::default());
+    kcp.update(100);
+    kcp.send(b"hello").unwrap();
+    kcp.flush();
+    #[rustfmt::skip]
+    let expected: &[u8] = &[
+        0x04, 0x03, 0x02, 0x01, // conv
+        PUSH,                   // cmd
+        0x00,                   // frg
+        0x00, 0x04,             // wnd = 1024
+        0x64, 0x00, 0x00, 0x00, // ts = 100
+        0x00, 0x00, 0x00, 0x00, // sn = 0
+        0x00, 0x00, 0x00, 0x00, // una = 0
+        0x05, 0x00, 0x00, 0x00, // len = 5
+        b'h', b'e', b'l', b'l', b'o',
+    ];
+    assert_eq!(outputs(&mut kcp), vec![expected.to_vec()]);
+    assert_eq!(conv_from_raw(expected), 0x0102_0304);
+}
+
+#[test]
+fn message_fragmentation() {
+    let config = Config {
+        mtu: 64,
+        ..Default::default()
+    };
+    let message: Vec<u8> = (0..100).collect();
+    let mut kcp = ControlBlock::new(7, config.clone());
+    kcp.update(1000);
+    kcp.send(&message).unwrap();
+    kcp.flush();
+    let packets = outputs(&mut kcp);
+    // mss = 64 - 24 = 40, and a full segment fills a packet
+    assert_eq!(packets.len(), 3);
+    let expected = [(2, 0, 0..40), (1, 1, 40..80), (0, 2, 80..100)];
+    for (packet, (frg, sn, range)) in packets.iter().zip(expected.iter().cloned()) {
+        let segs = segments(packet);
+        assert_eq!(segs.len(), 1);
+        let (header, data) = &segs[0];
+        assert_eq!(
+            header,
+            &Header {
+                conv: 7,
+                cmd: PUSH,
+                frg,
+                wnd: 1024,
+                ts: 1000,
+                sn,
+                una: 0,
+                len: range.len() as u32,
+            }
+        );
+        assert_eq!(data, &message[range]);
+    }
+
+    // Reassembly waits for the last fragment
+    let mut peer = ControlBlock::new(7, config);
+    peer.update(0);
+    peer.input(&packets[0]).unwrap();
+    peer.input(&packets[1]).unwrap();
+    assert!(matches!(peer.recv(), Err(Error::NotAvailable)));
+    peer.input(&packets[2]).unwrap();
+    assert_eq!(peer.recv().unwrap(), message);
+}
+
+#[test]
+fn stream_mode() {
+    let config = Config {
+        mtu: 64,
+        stream: true,
+        send_wnd: 1,
+        ..Default::default()
+    };
+    let mut kcp = ControlBlock::new(7, config);
+    kcp.update(1000);
+    // Every segment of a stream carries frg = 0
+    let data: Vec<u8> = (0..50).collect();
+    kcp.send(&data).unwrap();
+    kcp.flush();
+    let packets = outputs(&mut kcp);
+    assert_eq!(packets.len(), 1);
+    let segs = segments(&packets[0]);
+    assert_eq!(segs.len(), 1);
+    assert_eq!((segs[0].0.frg, segs[0].0.sn, segs[0].0.len), (0, 0, 40));
+
+    // The rest waits in the send queue, where later sends are merged into it
+    kcp.send(b"ab").unwrap();
+    kcp.update(1010);
+    #[rustfmt::skip]
+    let ack: &[u8] = &[
+        0x07, 0x00, 0x00, 0x00, ACK, 0x00, 0x80, 0x00, // wnd = 128
+        0xe8, 0x03, 0x00, 0x00, // ts = 1000
+        0x00, 0x00, 0x00, 0x00, // sn = 0
+        0x01, 0x00, 0x00, 0x00, // una = 1
+        0x00, 0x00, 0x00, 0x00,
+    ];
+    kcp.input(ack).unwrap();
+    kcp.flush();
+    let packets = outputs(&mut kcp);
+    assert_eq!(packets.len(), 1);
+    let segs = segments(&packets[0]);
+    assert_eq!(segs.len(), 1);
+    let (header, payload) = &segs[0];
+    assert_eq!(
+        header,
+        &Header {
+            conv: 7,
+            cmd: PUSH,
+            frg: 0,
+            wnd: 1024,
+            ts: 1010,
+            sn: 1,
+            una: 0,
+            len: 12,
+        }
+    );
+    assert_eq!(&payload[..10], &data[40..]);
+    assert_eq!(&payload[10..], b"ab");
+}
+
+#[test]
+fn ack_layout() {
+    let mut kcp = ControlBlock::new(0xdead_beef, Config::default());
+    kcp.update(20);
+    // A two-fragment message, packed into one packet by ikcp_flush
+    #[rustfmt::skip]
+    let packet: &[u8] = &[
+        0xef, 0xbe, 0xad, 0xde, PUSH, 0x01, 0x80, 0x00,
+        0x88, 0x13, 0x00, 0x00, // ts = 5000
+        0x00, 0x00, 0x00, 0x00, // sn = 0
+        0x00, 0x00, 0x00, 0x00,
+        0x03, 0x00, 0x00, 0x00, b'f', b'o', b'o',
+        0xef, 0xbe, 0xad, 0xde, PUSH, 0x00, 0x80, 0x00,
+        0x88, 0x13, 0x00, 0x00, // ts = 5000
+        0x01, 0x00, 0x00, 0x00, // sn = 1
+        0x00, 0x00, 0x00, 0x00,
+        0x03, 0x00, 0x00, 0x00, b'b', b'a', b'r',
+    ];
+    assert_eq!(kcp.input(packet).unwrap(), packet.len());
+    kcp.flush();
+    #[rustfmt::skip]
+    let expected: &[u8] = &[
+        0xef, 0xbe, 0xad, 0xde, ACK, 0x00,
+        0xfe, 0x03,             // wnd = 1024 - 2 segments not yet received
+        0x88, 0x13, 0x00, 0x00, // ts echoed
+        0x00, 0x00, 0x00, 0x00, // sn = 0
+        0x02, 0x00, 0x00, 0x00, // una = 2
+        0x00, 0x00, 0x00, 0x00,
+        0xef, 0xbe, 0xad, 0xde, ACK, 0x00,
+        0xfe, 0x03,
+        0x88, 0x13, 0x00, 0x00,
+        0x01, 0x00, 0x00, 0x00, // sn = 1
+        0x02, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00,
+    ];
+    assert_eq!(outputs(&mut kcp), vec![expected.to_vec()]);
+    assert_eq!(kcp.recv().unwrap(), b"foobar");
+    assert_eq!(kcp.stats().bytes_received, 6);
+}
+
+#[test]
+fn acked_segments_leave_the_send_buffer() {
+    let mut kcp = ControlBlock::new(1, Config::default());
+    kcp.update(0);
+    kcp.send(b"x").unwrap();
+    kcp.send(b"y").unwrap();
+    kcp.flush();
+    assert_eq!(kcp.wait_send(), 2);
+    kcp.update(50);
+    #[rustfmt::skip]
+    let ack: &[u8] = &[
+        0x01, 0x00, 0x00, 0x00, ACK, 0x00, 0x80, 0x00,
+        0x00, 0x00, 0x00, 0x00, // ts = 0
+        0x01, 0x00, 0x00, 0x00, // sn = 1
+        0x00, 0x00, 0x00, 0x00, // una = 0, sn 0 still missing
+        0x00, 0x00, 0x00, 0x00,
+    ];
+    kcp.input(ack).unwrap();
+    assert_eq!(kcp.wait_send(), 1);
+    let mut ack = ack.to_vec();
+    ack[12] = 0; // sn = 0
+    ack[16] = 2; // una = 2
+    kcp.input(&ack).unwrap();
+    assert_eq!(kcp.wait_send(), 0);
+    assert!(kcp.all_flushed());
+    let stats = kcp.stats();
+    assert_eq!(stats.segments_sent, 2);
+    assert_eq!(stats.segments_resent, 0);
+    assert_eq!(stats.bytes_acked, 2);
+    assert_eq!(stats.inflight, 0);
+}
+
+#[test]
+fn window_probes() {
+    let mut kcp = ControlBlock::new(9, Config::default());
+    kcp.update(0);
+    #[rustfmt::skip]
+    let wask: &[u8] = &[
+        0x09, 0x00, 0x00, 0x00, WASK, 0x00, 0x80, 0x00,
+        0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00,
+    ];
+    kcp.input(wask).unwrap();
+    kcp.flush();
+    #[rustfmt::skip]
+    let wins: &[u8] = &[
+        0x09, 0x00, 0x00, 0x00, WINS, 0x00, 0x00, 0x04, // wnd = 1024
+        0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00,
+    ];
+    assert_eq!(outputs(&mut kcp), vec![wins.to_vec()]);
+
+    // A zero window from the other side is probed after IKCP_PROBE_INIT (7 s)
+    let mut closed = wins.to_vec();
+    closed[6] = 0;
+    closed[7] = 0;
+    kcp.input(&closed).unwrap();
+    kcp.flush();
+    assert!(outputs(&mut kcp).is_empty());
+    kcp.update(7000);
+    kcp.flush();
+    let mut expected = wask.to_vec();
+    expected[6] = 0x00;
+    expected[7] = 0x04;
+    assert_eq!(outputs(&mut kcp), vec![expected]);
+}
+
+#[test]
+fn wrong_conv() {
+    let mut kcp = ControlBlock::new(1, Config::default());
+    kcp.update(0);
+    let mut packet = vec![0; 24];
+    packet[0] = 2;
+    packet[4] = WASK;
+    assert!(matches!(
+        kcp.input(&packet),
+        Err(Error::WrongConv {
+            expected: 1,
+            found: 2
+        })
+    ));
+}
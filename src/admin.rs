@@ -0,0 +1,253 @@
+/*
+Copyright 2021 Chengyuan Ma
+
+Permission is hereby granted, free of charge, to any person obtaining a copy of this software and
+associated documentation files (the "Software"), to deal in the Software without restriction,
+including without limitation the rights to use, copy, modify, merge, publish, distribute, sub-
+-license, and/or sell copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all copies or substantial
+portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING BUT
+NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NON-
+-INFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES
+OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+*/
+
+//! The admin socket.
+//!
+//! A running instance can be inspected and steered through a Unix domain socket, which only its
+//! owner may connect to:
+//!
+//! ```toml
+//! [admin]
+//! socket = "/run/ekho.sock"
+//! ```
+//!
+//! The protocol is line-delimited JSON. Each line sent is a [Request], answered by a line with
+//! either `{"ok": ...}` or `{"error": "..."}`:
+//!
+//! ```text
+//! > {"command": "sessions"}
+//! < {"ok": [{"peer": {"ip": "203.0.113.1", "id": 1234}, "conv": 42, "age": 12.5, "stats": {...}}]}
+//! > {"command": "kill", "peer": {"ip": "203.0.113.1", "id": 1234}, "conv": 42}
+//! < {"ok": null}
+//! > {"command": "log_level", "level": "debug"}
+//! < {"ok": null}
+//! > {"command": "reload"}
+//! < {"ok": {"restart_required": ["icmp"]}}
+//! ```
+//!
+//! `ekho ctl` speaks this protocol.
+
+use crate::config::Config as EkhoConfig;
+use crate::ekho::Ekho;
+use crate::icmp::Endpoint;
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+#[cfg(unix)]
+use socket2::{Domain, SockAddr, Socket, Type};
+#[cfg(unix)]
+use std::fs::{self, Permissions};
+use std::future::Future;
+#[cfg(unix)]
+use std::io;
+#[cfg(unix)]
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+#[cfg(unix)]
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use tokio::task;
+use tracing::info;
+#[cfg(unix)]
+use tracing::{debug, error};
+
+/// Admin socket configuration.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Config {
+    /// Where to create the socket. There is no admin socket if unset.
+    pub socket: Option<PathBuf>,
+}
+
+/// A request sent to the admin socket.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum Request {
+    /// Lists the live sessions with their statistics.
+    Sessions,
+    /// Resets a session.
+    Kill { peer: Endpoint, conv: u32 },
+    /// Shows the configuration in effect, without the key and passwords.
+    Config,
+    /// Changes the log level until the configuration is reloaded.
+    LogLevel { level: String },
+    /// Loads the configuration again and puts it into effect, like SIGHUP. Answers with the
+    /// [Reload](crate::ekho::Reload) report.
+    Reload,
+}
+
+/// Loads the configuration again for [Request::Reload], the way it was loaded on startup.
+pub type Loader =
+    Arc<dyn Fn() -> Pin<Box<dyn Future<Output = Result<EkhoConfig>> + Send>> + Send + Sync>;
+
+/// The answer to a [Request].
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Response {
+    Ok(Value),
+    Error(String),
+}
+
+async fn execute(ekho: &Ekho, loader: &Loader, request: Request) -> Result<Value> {
+    Ok(match request {
+        Request::Sessions => serde_json::to_value(ekho.sessions().await)?,
+        Request::Kill { peer, conv } => {
+            if !ekho.kill(peer, conv).await {
+                bail!("no session {}@{}", peer, conv);
+            }
+            Value::Null
+        }
+        Request::Config => serde_json::to_value(&*ekho.config())?,
+        Request::LogLevel { level } => {
+            crate::log::set_level(level.parse().context("invalid log level")?);
+            Value::Null
+        }
+        Request::Reload => {
+            let reload = ekho.reload(loader().await?)?;
+            info!("{} (requested through the admin socket)", reload);
+            serde_json::to_value(reload)?
+        }
+    })
+}
+
+/// Creates the admin socket of `ekho` if one is configured, and starts answering requests on it.
+/// `loader` serves [Request::Reload].
+#[cfg(unix)]
+pub async fn start(ekho: &Ekho, loader: Loader) -> Result<()> {
+    let path = match &ekho.config().admin.socket {
+        Some(path) => path.clone(),
+        None => return Ok(()),
+    };
+    if UnixStream::connect(&path).await.is_ok() {
+        bail!("admin socket {} is in use", path.display());
+    }
+    // Left behind by an instance that did not exit cleanly
+    if matches!(fs::symlink_metadata(&path), Ok(meta) if meta.file_type().is_socket()) {
+        fs::remove_file(&path)?;
+    }
+    let listener =
+        bind(&path).with_context(|| format!("binding admin socket on {}", path.display()))?;
+    info!("serving admin requests on {}", path.display());
+    task::spawn(serve(ekho.clone(), loader, listener));
+    Ok(())
+}
+
+/// Binds a Unix domain socket at `path` that only its owner may connect to. Its mode is set before
+/// it starts listening, as anyone allowed by the umask could connect in between otherwise.
+#[cfg(unix)]
+fn bind(path: &Path) -> io::Result<UnixListener> {
+    let socket = Socket::new(Domain::UNIX, Type::STREAM, None)?;
+    socket.bind(&SockAddr::unix(path)?)?;
+    fs::set_permissions(path, Permissions::from_mode(0o600))?;
+    socket.listen(128)?;
+    socket.set_nonblocking(true)?;
+    UnixListener::from_std(socket.into())
+}
+
+#[cfg(not(unix))]
+pub async fn start(ekho: &Ekho, _loader: Loader) -> Result<()> {
+    if ekho.config().admin.socket.is_some() {
+        bail!("the admin socket is only supported on Unix");
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+async fn serve(ekho: Ekho, loader: Loader, listener: UnixListener) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                let ekho = ekho.clone();
+                let loader = loader.clone();
+                task::spawn(async move {
+                    if let Err(err) = handle(&ekho, &loader, stream).await {
+                        debug!("error serving admin connection: {}", err);
+                    }
+                });
+            }
+            Err(err) => error!("error accepting admin connection: {}", err),
+        }
+    }
+}
+
+#[cfg(unix)]
+async fn handle(ekho: &Ekho, loader: &Loader, stream: UnixStream) -> Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    while let Some(line) = lines.next_line().await? {
+        let response = match serde_json::from_str(&line) {
+            Ok(request) => match execute(ekho, loader, request).await {
+                Ok(value) => Response::Ok(value),
+                Err(err) => Response::Error(format!("{:#}", err)),
+            },
+            Err(err) => Response::Error(format!("invalid request: {}", err)),
+        };
+        let mut buf = serde_json::to_vec(&response)?;
+        buf.push(b'\n');
+        writer.write_all(&buf).await?;
+    }
+    Ok(())
+}
+
+/// Sends `request` to the admin socket at `path`, returning what is in the `ok` response.
+#[cfg(unix)]
+pub async fn request(path: &Path, request: &Request) -> Result<Value> {
+    let stream = UnixStream::connect(path)
+        .await
+        .with_context(|| format!("connecting to {}", path.display()))?;
+    let (reader, mut writer) = stream.into_split();
+    let mut buf = serde_json::to_vec(request)?;
+    buf.push(b'\n');
+    writer.write_all(&buf).await?;
+    let line = BufReader::new(reader)
+        .lines()
+        .next_line()
+        .await?
+        .context("connection closed without a response")?;
+    match serde_json::from_str(&line).context("invalid response")? {
+        Response::Ok(value) => Ok(value),
+        Response::Error(err) => bail!(err),
+    }
+}
+
+#[cfg(not(unix))]
+pub async fn request(_path: &Path, _request: &Request) -> Result<Value> {
+    bail!("the admin socket is only supported on Unix")
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn bind_owner_only() {
+        let path = std::env::temp_dir().join(format!("ekho-admin-{}.sock", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let listener = bind(&path).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        let (_, accepted) = tokio::join!(UnixStream::connect(&path), listener.accept());
+        accepted.unwrap();
+        fs::remove_file(&path).unwrap();
+    }
+}
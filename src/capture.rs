@@ -0,0 +1,194 @@
+/*
+Copyright 2021 Chengyuan Ma
+
+Permission is hereby granted, free of charge, to any person obtaining a copy of this software and
+associated documentation files (the "Software"), to deal in the Software without restriction,
+including without limitation the rights to use, copy, modify, merge, publish, distribute, sub-
+-license, and/or sell copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all copies or substantial
+portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING BUT
+NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NON-
+-INFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES
+OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+*/
+
+//! Packet capture.
+//!
+//! Every KCP packet sent or received can be written, decrypted, to a pcapng file:
+//!
+//! ```toml
+//! [capture]
+//! file = "ekho.pcapng"
+//! ```
+//!
+//! Packets are wrapped in IPv4 and UDP headers, so that each peer shows up as a conversation of
+//! its own: the peer's end has its IP and its echo identifier as the port, and the local end is
+//! `0.0.0.0` port [PORT]. The sessions with a peer are told apart by the conv of their segments.
+//! The Wireshark dissector in `contrib/kcp.lua` decodes the KCP headers:
+//!
+//! ```sh
+//! wireshark -X lua_script:contrib/kcp.lua ekho.pcapng
+//! ```
+//!
+//! The file holds the plaintext of everything going through the tunnel, so only its owner may read
+//! it. It is overwritten on start. Packets are written by a thread of their own, and dropped if it
+//! falls behind.
+
+use crate::icmp::Endpoint;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions, Permissions};
+use std::io::{self, BufWriter, Write};
+use std::net::Ipv4Addr;
+#[cfg(unix)]
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tracing::{debug, error};
+
+/// The UDP port of the local end of captured packets, that of the echo protocol.
+pub const PORT: u16 = 7;
+/// `LINKTYPE_RAW`: packets start with an IPv4 header.
+const LINKTYPE_RAW: u16 = 101;
+/// How many packets may wait for the writing thread.
+const QUEUE_LEN: usize = 4096;
+
+/// Capture configuration.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Config {
+    /// Where to write captured packets. Nothing is captured if unset.
+    pub file: Option<PathBuf>,
+}
+
+/// Whether a packet was sent to or received from the peer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+/// A pcapng file being written.
+#[derive(Debug)]
+pub struct Capture(Sender<Vec<u8>>);
+
+impl Capture {
+    /// Creates the file at `path` and writes its headers.
+    pub fn create(path: &Path) -> Result<Capture> {
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        options.mode(0o600);
+        let file = options
+            .open(path)
+            .with_context(|| format!("creating {}", path.display()))?;
+        // The mode only applies to new files
+        #[cfg(unix)]
+        file.set_permissions(Permissions::from_mode(0o600))?;
+        let mut file = BufWriter::new(file);
+        // Section header block: byte-order magic, version 1.0, unknown section length
+        let mut body = Vec::with_capacity(16);
+        body.extend_from_slice(&0x1a2b_3c4du32.to_le_bytes());
+        body.extend_from_slice(&1u16.to_le_bytes());
+        body.extend_from_slice(&0u16.to_le_bytes());
+        body.extend_from_slice(&(-1i64).to_le_bytes());
+        file.write_all(&block(0x0a0d_0d0a, &body))?;
+        // Interface description block: link type, reserved, no snapshot length limit
+        let mut body = Vec::with_capacity(8);
+        body.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+        body.extend_from_slice(&0u16.to_le_bytes());
+        body.extend_from_slice(&0u32.to_le_bytes());
+        file.write_all(&block(1, &body))?;
+        file.flush()?;
+        let (tx, rx) = channel(QUEUE_LEN);
+        thread::spawn(move || {
+            if let Err(err) = write_loop(file, rx) {
+                error!("error writing capture, stopping: {}", err);
+            }
+        });
+        Ok(Capture(tx))
+    }
+
+    /// Writes a (decrypted) KCP packet exchanged with `peer`.
+    pub fn write(&self, peer: Endpoint, direction: Direction, packet: &[u8]) {
+        let local = (Ipv4Addr::UNSPECIFIED, PORT);
+        let peer = (peer.ip, peer.id);
+        let (src, dst) = match direction {
+            Direction::Sent => (local, peer),
+            Direction::Received => (peer, local),
+        };
+        let datagram = udp_in_ipv4(src, dst, packet);
+        let micros = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64;
+        // Enhanced packet block: interface, timestamp, captured and original length, data
+        let mut body = Vec::with_capacity(20 + datagram.len() + 3);
+        body.extend_from_slice(&0u32.to_le_bytes());
+        body.extend_from_slice(&((micros >> 32) as u32).to_le_bytes());
+        body.extend_from_slice(&(micros as u32).to_le_bytes());
+        body.extend_from_slice(&(datagram.len() as u32).to_le_bytes());
+        body.extend_from_slice(&(datagram.len() as u32).to_le_bytes());
+        body.extend_from_slice(&datagram);
+        body.resize(body.len() + (4 - body.len() % 4) % 4, 0);
+        if let Err(TrySendError::Full(_)) = self.0.try_send(block(6, &body)) {
+            debug!("capture falling behind, dropping packet");
+        }
+    }
+}
+
+/// Writes blocks to `file` until the [Capture] is dropped, flushing whenever there are none left
+/// to write, so that the file can be read while capturing.
+fn write_loop(mut file: BufWriter<File>, mut rx: Receiver<Vec<u8>>) -> io::Result<()> {
+    while let Some(block) = rx.blocking_recv() {
+        file.write_all(&block)?;
+        while let Ok(block) = rx.try_recv() {
+            file.write_all(&block)?;
+        }
+        file.flush()?;
+    }
+    Ok(())
+}
+
+/// Frames a pcapng block of type `kind`. `body` must be padded to 32 bits.
+fn block(kind: u32, body: &[u8]) -> Vec<u8> {
+    let len = (body.len() as u32 + 12).to_le_bytes();
+    let mut block = Vec::with_capacity(body.len() + 12);
+    block.extend_from_slice(&kind.to_le_bytes());
+    block.extend_from_slice(&len);
+    block.extend_from_slice(body);
+    block.extend_from_slice(&len);
+    block
+}
+
+/// Wraps `payload` in a UDP datagram from `src` to `dst`, in an IPv4 packet.
+fn udp_in_ipv4(src: (Ipv4Addr, u16), dst: (Ipv4Addr, u16), payload: &[u8]) -> Vec<u8> {
+    let udp_len = 8 + payload.len() as u16;
+    let mut packet = Vec::with_capacity(20 + udp_len as usize);
+    packet.extend_from_slice(&[0x45, 0]);
+    packet.extend_from_slice(&(20 + udp_len).to_be_bytes());
+    // Identification; don't fragment; TTL 64; protocol UDP; checksum, filled in below
+    packet.extend_from_slice(&[0, 0, 0x40, 0, 64, 17, 0, 0]);
+    packet.extend_from_slice(&src.0.octets());
+    packet.extend_from_slice(&dst.0.octets());
+    let checksum = !packet.chunks(2).fold(0u32, |sum, word| {
+        let sum = sum + u16::from_be_bytes([word[0], word[1]]) as u32;
+        (sum & 0xffff) + (sum >> 16)
+    }) as u16;
+    packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+    packet.extend_from_slice(&src.1.to_be_bytes());
+    packet.extend_from_slice(&dst.1.to_be_bytes());
+    packet.extend_from_slice(&udp_len.to_be_bytes());
+    // The UDP checksum is optional over IPv4
+    packet.extend_from_slice(&[0, 0]);
+    packet.extend_from_slice(payload);
+    packet
+}
@@ -17,20 +17,30 @@ OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
 CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
 */
 
-use crate::config::config;
+use crate::ekho::Ekho;
+use crate::forward;
+use crate::http::handle_http;
+use crate::listen::{self, Auth, Protocol};
 use crate::relay::{relay_kcp, relay_tcp};
+use crate::route::Action;
 use crate::session::Session;
 use crate::socks5::{
-    Socks5Command, Socks5Error, Socks5Reply, Socks5Request, Socks5SocketAddr, SOCKS5_VERSION,
+    Socks5Command, Socks5Error, Socks5PasswordAuth, Socks5Reply, Socks5Request, Socks5SocketAddr,
+    SOCKS5_AUTH_NONE, SOCKS5_AUTH_PASSWORD, SOCKS5_AUTH_UNACCEPTABLE, SOCKS5_PASSWORD_VERSION,
+    SOCKS5_VERSION,
 };
+use crate::transparent::original_dst;
+use crate::tun;
+use crate::udp::relay_udp_local;
 use anyhow::{bail, Context, Result};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::select;
 use tokio::task;
-use tracing::{debug, error, instrument};
+use tracing::{debug, error, info, instrument};
 
-#[instrument(skip(local), fields(local = "local.peer_addr().unwrap()"))]
-async fn handle_socks(mut local: TcpStream) -> Result<()> {
+#[instrument(skip(ekho, local, auth), fields(local = "local.peer_addr().unwrap()"))]
+async fn handle_socks(ekho: &Ekho, mut local: TcpStream, auth: Option<&Auth>) -> Result<()> {
     let mut buf = [0; 1024];
     let len = local
         .read(&mut buf)
@@ -39,16 +49,44 @@ async fn handle_socks(mut local: TcpStream) -> Result<()> {
     if len < 2 || len != buf[1] as usize + 2 || buf[0] != SOCKS5_VERSION {
         bail!("invalid SOCKS5 greeting message from {:?}", local);
     }
-    if !buf[2..len].contains(&0) {
+    let method = if auth.is_some() {
+        SOCKS5_AUTH_PASSWORD
+    } else {
+        SOCKS5_AUTH_NONE
+    };
+    if !buf[2..len].contains(&method) {
+        local
+            .write_all(&[SOCKS5_VERSION, SOCKS5_AUTH_UNACCEPTABLE])
+            .await
+            .context("sending back SOCKS5 method selection")?;
         bail!(
-            "SOCKS5 client from {:?} does not support NO AUTH method",
-            local
+            "SOCKS5 client from {:?} does not support method {}",
+            local,
+            method
         );
     }
     local
-        .write_all(&[SOCKS5_VERSION, 0])
+        .write_all(&[SOCKS5_VERSION, method])
         .await
         .context("sending back SOCKS5 method selection")?;
+    if let Some(auth) = auth {
+        let len = local
+            .read(&mut buf)
+            .await
+            .context("reading SOCKS5 username/password")?;
+        let request = Socks5PasswordAuth::parse(&buf[..len])?;
+        let ok = request.username == auth.username && request.password == auth.password;
+        local
+            .write_all(&[SOCKS5_PASSWORD_VERSION, if ok { 0 } else { 1 }])
+            .await
+            .context("replying SOCKS5 authentication")?;
+        if !ok {
+            bail!(
+                "SOCKS5 authentication failed for user {:?}",
+                request.username
+            );
+        }
+    }
     let len = local
         .read(&mut buf)
         .await
@@ -57,51 +95,87 @@ async fn handle_socks(mut local: TcpStream) -> Result<()> {
     debug!("{:?}", request);
     match request.cmd {
         Socks5Command::Connect => {
-            if connect_directly(&request.dst) {
-                match request.dst.connect().await {
-                    Ok(remote) => {
-                        local
-                            .write_all(
-                                &Socks5Reply::Success {
-                                    bnd: remote.local_addr()?.into(),
-                                }
-                                .marshal(),
-                            )
-                            .await
-                            .context("replying SOCKS5 client (success)")?;
-                        relay_tcp(local, remote)
-                            .await
-                            .context("relaying TCP traffic")?;
-                    }
-                    Err(err) => {
-                        error!(
-                            "error while connecting to remote host {}: {}",
-                            request.dst, err
-                        );
-                        local
-                            .write_all(&Socks5Reply::Error(err.into()).marshal())
-                            .await
-                            .context("replying SOCKS5 client (error)")?;
-                    }
-                }
+            let (reply, outbound) = connect(ekho, &request.dst).await?;
+            ekho.metrics().socks_reply(&reply);
+            local
+                .write_all(&reply.marshal())
+                .await
+                .context("replying SOCKS5 client")?;
+            if let Some(outbound) = outbound {
+                outbound.relay(ekho, local).await?;
+            }
+        }
+        // BIND always goes through the server, so only rejection is honored here
+        Socks5Command::Bind if ekho.config().routing.route(&request.dst) == Action::Reject => {
+            let reply = Socks5Reply::Error(Socks5Error::ConnectionNotAllowed);
+            ekho.metrics().socks_reply(&reply);
+            local
+                .write_all(&reply.marshal())
+                .await
+                .context("replying SOCKS5 client (not allowed)")?;
+        }
+        Socks5Command::Bind => {
+            let (session, reply) = request_remote(ekho, &request).await?;
+            ekho.metrics().socks_reply(&reply);
+            local
+                .write_all(&reply.marshal())
+                .await
+                .context("forwarding first reply from server")?;
+            if let Socks5Reply::Error(_) = reply {
+                session.close().await;
+                return Ok(());
+            }
+            // Wait for the server to accept the inbound connection, unless the client hangs up.
+            let mut peek_buf = [0; 1];
+            let second = select! {
+                data = session.recv() => data,
+                Ok(0) = local.peek(&mut peek_buf) => Vec::new(),
+            };
+            if second.is_empty() {
+                session.close().await;
+                return Ok(());
+            }
+            let reply = Socks5Reply::parse(&second)?;
+            local
+                .write_all(&reply.marshal())
+                .await
+                .context("forwarding second reply from server")?;
+            if let Socks5Reply::Success { .. } = reply {
+                relay_kcp(local, session).await?;
+            } else {
+                session.close().await;
+            }
+        }
+        Socks5Command::UdpAssociate => {
+            let socket = UdpSocket::bind((local.local_addr()?.ip(), 0))
+                .await
+                .context("binding UDP relay socket")?;
+            let (session, reply) = request_remote(ekho, &request).await?;
+            ekho.metrics().socks_reply(&reply);
+            if let Socks5Reply::Success { .. } = reply {
+                local
+                    .write_all(
+                        &Socks5Reply::Success {
+                            bnd: socket.local_addr()?.into(),
+                        }
+                        .marshal(),
+                    )
+                    .await
+                    .context("replying SOCKS5 client (UDP associate)")?;
+                relay_udp_local(local, socket, session).await?;
             } else {
-                let session = Session::connect(config().remote.unwrap());
-                session.send(&request.marshal()).await;
-                let reply = Socks5Reply::parse(&session.recv().await)?;
                 local
                     .write_all(&reply.marshal())
                     .await
                     .context("forwarding reply from server")?;
-                if let Socks5Reply::Success { .. } = reply {
-                    relay_kcp(local, session).await?;
-                } else {
-                    session.close().await;
-                }
+                session.close().await;
             }
         }
-        _ => {
+        Socks5Command::RemoteForward | Socks5Command::Tun => {
+            let reply = Socks5Reply::Error(Socks5Error::CommandNotSupported);
+            ekho.metrics().socks_reply(&reply);
             local
-                .write_all(&Socks5Reply::Error(Socks5Error::CommandNotSupported).marshal())
+                .write_all(&reply.marshal())
                 .await
                 .context("replying SOCKS5 client (command not supported)")?;
         }
@@ -109,20 +183,200 @@ async fn handle_socks(mut local: TcpStream) -> Result<()> {
     Ok(())
 }
 
-pub fn connect_directly(_addr: &Socks5SocketAddr) -> bool {
-    false
+/// An established outbound connection for a CONNECT-like request.
+pub enum Outbound {
+    /// Connected to the destination directly.
+    Direct(TcpStream),
+    /// Connected to the destination through the server.
+    Remote(Session),
 }
 
-#[instrument]
-pub async fn run() {
-    let listener = TcpListener::bind("127.0.0.1:23336").await.unwrap();
-    loop {
-        if let Ok((stream, _)) = listener.accept().await {
-            task::spawn(async move {
-                if let Err(err) = handle_socks(stream).await {
-                    error!("{}", err);
+impl Outbound {
+    /// Sends `buf` to the destination ahead of relaying.
+    pub async fn write_all(&mut self, buf: &[u8]) -> Result<()> {
+        match self {
+            Outbound::Direct(remote) => remote.write_all(buf).await?,
+            Outbound::Remote(session) => {
+                for chunk in buf.chunks(session.ekho().config().kcp.mss()) {
+                    session.send(chunk).await;
                 }
-            });
+            }
+        }
+        Ok(())
+    }
+
+    /// Relays traffic between `local` and the destination until either side finishes.
+    pub async fn relay(self, ekho: &Ekho, local: TcpStream) -> Result<()> {
+        match self {
+            Outbound::Direct(remote) => relay_tcp(local, remote, &ekho.config().relay)
+                .await
+                .context("relaying TCP traffic"),
+            Outbound::Remote(session) => relay_kcp(local, session).await,
+        }
+    }
+}
+
+/// Connects to `dst`, either directly or through the server.
+///
+/// Returns the reply to be sent to the local client, along with the outbound connection if the
+/// attempt succeeded.
+pub async fn connect(
+    ekho: &Ekho,
+    dst: &Socks5SocketAddr,
+) -> Result<(Socks5Reply, Option<Outbound>)> {
+    match ekho.config().routing.route(dst) {
+        Action::Reject => {
+            debug!("rejecting request to {}", dst);
+            Ok((Socks5Reply::Error(Socks5Error::ConnectionNotAllowed), None))
         }
+        Action::Direct => match dst.connect().await {
+            Ok(remote) => Ok((
+                Socks5Reply::Success {
+                    bnd: remote.local_addr()?.into(),
+                },
+                Some(Outbound::Direct(remote)),
+            )),
+            Err(err) => {
+                error!("error while connecting to remote host {}: {}", dst, err);
+                Ok((Socks5Reply::Error(err.into()), None))
+            }
+        },
+        Action::Proxy => {
+            let request = Socks5Request {
+                cmd: Socks5Command::Connect,
+                dst: dst.clone(),
+            };
+            let (session, reply) = request_remote(ekho, &request).await?;
+            if let Socks5Reply::Success { .. } = reply {
+                Ok((reply, Some(Outbound::Remote(session))))
+            } else {
+                session.close().await;
+                Ok((reply, None))
+            }
+        }
+    }
+}
+
+/// Opens a new session to the server and sends `request` over it, returning the session together
+/// with the server's reply.
+async fn request_remote(ekho: &Ekho, request: &Socks5Request) -> Result<(Session, Socks5Reply)> {
+    let session = ekho.connect(ekho.config().remote.unwrap());
+    session.send(&request.marshal()).await;
+    let reply = Socks5Reply::parse(&session.recv().await)?;
+    Ok((session, reply))
+}
+
+/// Forwards a connection to a fixed destination through the server.
+#[instrument(skip(ekho, local), fields(local = "local.peer_addr().unwrap()"))]
+async fn handle_forward(ekho: &Ekho, local: TcpStream, target: &Socks5SocketAddr) -> Result<()> {
+    let request = Socks5Request {
+        cmd: Socks5Command::Connect,
+        dst: target.clone(),
+    };
+    let (session, reply) = request_remote(ekho, &request).await?;
+    if let Socks5Reply::Success { .. } = reply {
+        relay_kcp(local, session).await
+    } else {
+        session.close().await;
+        bail!("server failed to connect to {}: {:?}", target, reply);
     }
 }
+
+/// Proxies a connection diverted to a `redirect` or `tproxy` listener to its original destination.
+#[instrument(skip(ekho, local), fields(local = "local.peer_addr().unwrap()"))]
+async fn handle_transparent(ekho: &Ekho, local: TcpStream, listen: &listen::Config) -> Result<()> {
+    let dst = if listen.protocol == Protocol::Redirect {
+        original_dst(&local).context("getting original destination")?
+    } else {
+        local.local_addr()?
+    };
+    // Connecting to the listener itself would loop forever
+    if dst.port() == listen.address.port()
+        && (listen.address.ip().is_unspecified() || dst.ip() == listen.address.ip())
+    {
+        bail!("connection to {} was not diverted", dst);
+    }
+    let dst = dst.into();
+    debug!("original destination {}", dst);
+    match connect(ekho, &dst).await? {
+        (_, Some(outbound)) => outbound.relay(ekho, local).await,
+        (reply, None) => bail!("failed to connect to {}: {:?}", dst, reply),
+    }
+}
+
+async fn handle_inbound(ekho: &Ekho, local: TcpStream, listen: &listen::Config) -> Result<()> {
+    let auth = listen.auth.as_ref();
+    match listen.protocol {
+        Protocol::Socks5 => handle_socks(ekho, local, auth).await,
+        Protocol::Http => handle_http(ekho, local, auth).await,
+        Protocol::Forward => handle_forward(ekho, local, listen.target.as_ref().unwrap()).await,
+        Protocol::Redirect | Protocol::Tproxy => handle_transparent(ekho, local, listen).await,
+        // SOCKS5 and HTTP are told apart by the first byte
+        Protocol::Mixed => {
+            let mut first = [0; 1];
+            if local
+                .peek(&mut first)
+                .await
+                .context("peeking inbound data")?
+                == 0
+            {
+                return Ok(());
+            }
+            if first[0] == SOCKS5_VERSION {
+                handle_socks(ekho, local, auth).await
+            } else {
+                handle_http(ekho, local, auth).await
+            }
+        }
+    }
+}
+
+/// Serves the `index`-th configured listener. Its configuration is looked up for each connection,
+/// so that reloaded credentials and targets apply right away.
+async fn serve(ekho: Ekho, listener: TcpListener, index: usize) {
+    let address = ekho.config().listen[index].address;
+    info!(
+        "listening on {} ({})",
+        address,
+        ekho.config().listen[index].protocol
+    );
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                let ekho = ekho.clone();
+                task::spawn(async move {
+                    let listen = ekho.config().listen[index].clone();
+                    if let Err(err) = handle_inbound(&ekho, stream, &listen).await {
+                        error!("{}", err);
+                    }
+                });
+            }
+            Err(err) => error!("error accepting connection on {}: {}", address, err),
+        }
+    }
+}
+
+#[instrument]
+pub async fn run(ekho: Ekho) -> Result<()> {
+    // Bind everything before serving anything, so that a bad address fails the startup
+    let mut listeners = Vec::new();
+    for (index, listen) in ekho.config().listen.iter().enumerate() {
+        let listener = listen.bind().with_context(|| {
+            format!("binding {} listener on {}", listen.protocol, listen.address)
+        })?;
+        listeners.push(task::spawn(serve(ekho.clone(), listener, index)));
+    }
+    if !ekho.config().remote_forward.is_empty() {
+        task::spawn(forward::run_incoming(ekho.clone()));
+        for remote_forward in &ekho.config().remote_forward {
+            task::spawn(forward::register(ekho.clone(), remote_forward.clone()));
+        }
+    }
+    if ekho.tun().is_some() {
+        task::spawn(tun::connect(ekho.clone()));
+    }
+    for listener in listeners {
+        listener.await?;
+    }
+    Ok(())
+}
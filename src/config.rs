@@ -17,26 +17,241 @@ OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
 CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
 */
 
-use crate::icmp::Endpoint;
-use anyhow::{Context, Result};
+//! Configuration.
+//!
+//! A config file can pull in shared fragments with `include`, and keep the key in a separate file
+//! with `key_file` instead of `key`. Relative paths are relative to the file they are written in.
+//!
+//! ```toml
+//! include = ["common/kcp.toml"]
+//! key_file = "/run/secrets/ekho-key"
+//! remote = { ip = "203.0.113.1", id = 1234 }
+//! ```
+//!
+//! Settings in a file override those it includes. Environment variables named
+//! `EKHO_SECTION__FIELD` override both, e.g. `EKHO_KCP__MTU=1200` or `EKHO_KEY_FILE=key.txt`.
+//! Their values are read as TOML where possible, so `EKHO_REMOTE='{ ip = "203.0.113.1", id = 1 }'`
+//! works too.
+
+use crate::icmp::{self, Endpoint};
+use crate::kcp::ConfigError;
+use crate::listen::Protocol;
+use anyhow::{anyhow, Context, Result};
 use chacha20poly1305::Key;
-use once_cell::sync::OnceCell;
+use rand::rngs::OsRng;
+use rand::RngCore;
 use serde::de::{Error, Visitor};
-use serde::{Deserialize, Deserializer};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::convert::TryInto;
 use std::fmt;
 use std::path::Path;
+use thiserror::Error;
+use tokio::task;
+use toml::Value;
+
+mod source;
+pub mod template;
+
+/// The longest timeout (unit: s), as those of sessions are counted in milliseconds by the `u32`
+/// clock of KCP.
+const MAX_TIMEOUT: u64 = u32::MAX as u64 / 1000;
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Serialize, Debug)]
 pub struct Config {
     #[serde(default)]
     pub remote: Option<Endpoint>,
     pub kcp: crate::kcp::Config,
     pub icmp: crate::icmp::Config,
-    #[serde(deserialize_with = "deserialize_key")]
+    #[serde(default)]
+    pub session: crate::session::Config,
+    #[serde(default)]
+    pub relay: crate::relay::Config,
+    #[serde(default)]
+    pub udp: crate::udp::Config,
+    #[serde(default)]
+    pub routing: crate::route::Config,
+    #[serde(default = "crate::listen::default_listen")]
+    pub listen: Vec<crate::listen::Config>,
+    #[serde(default)]
+    pub remote_forward: Vec<crate::forward::Config>,
+    #[serde(default)]
+    pub tun: Option<crate::tun::Config>,
+    #[serde(default)]
+    pub log: crate::log::Config,
+    #[serde(default)]
+    pub metrics: crate::metrics::Config,
+    #[serde(default)]
+    pub admin: crate::admin::Config,
+    #[serde(default)]
+    pub capture: crate::capture::Config,
+    /// Never serialized, so that dumping a configuration does not leak it.
+    #[serde(deserialize_with = "deserialize_key", skip_serializing)]
     pub key: Key,
 }
 
-static CONFIG: OnceCell<Config> = OnceCell::new();
+/// Every problem found in a configuration.
+#[derive(Debug, Error)]
+pub struct ValidationError(pub Vec<ConfigError>);
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid configuration:")?;
+        for err in &self.0 {
+            write!(f, "\n  {}", err)?;
+        }
+        Ok(())
+    }
+}
+
+impl Config {
+    /// Checks the whole configuration, so that every problem can be reported before anything
+    /// starts.
+    pub fn validate(&self) -> Result<(), ValidationError> {
+        let mut errors: Vec<_> = self
+            .kcp
+            .validate()
+            .into_iter()
+            .map(|err| err.within("kcp"))
+            .collect();
+        let kcp_valid = errors.is_empty();
+        errors.extend(
+            self.icmp
+                .validate()
+                .into_iter()
+                .map(|err| err.within("icmp")),
+        );
+        errors.extend(self.log.validate().into_iter().map(|err| err.within("log")));
+        let packet = self.kcp.mtu as usize + icmp::MAX_OVERHEAD;
+        if self.icmp.raw_buffer < packet {
+            errors.push(ConfigError::new(
+                "icmp.raw_buffer",
+                format!(
+                    "must be at least {} to hold a whole packet with kcp.mtu = {}",
+                    packet, self.kcp.mtu
+                ),
+            ));
+        }
+        for (field, timeout) in [
+            ("session.idle_timeout", self.session.idle_timeout),
+            ("relay.idle_timeout", self.relay.idle_timeout),
+            ("relay.half_close_timeout", self.relay.half_close_timeout),
+            ("udp.idle_timeout", self.udp.idle_timeout),
+        ] {
+            if timeout > MAX_TIMEOUT {
+                errors.push(ConfigError::new(
+                    field,
+                    format!("must be at most {} (or 0 to never time out)", MAX_TIMEOUT),
+                ));
+            }
+        }
+        // Keepalive probes count as activity, but only if they come before the session times out
+        if let Some(keepalive) = self.kcp.keepalive {
+            let idle_timeout = self.session.idle_timeout.saturating_mul(1000);
+            if idle_timeout > 0 && keepalive as u64 >= idle_timeout {
+                errors.push(ConfigError::new(
+                    "session.idle_timeout",
+                    format!("must be longer than kcp.keepalive ({} ms)", keepalive),
+                ));
+            }
+        }
+        for (i, listen) in self.listen.iter().enumerate() {
+            if listen.protocol == Protocol::Forward && listen.target.is_none() {
+                errors.push(ConfigError::new(
+                    format!("listen[{}].target", i),
+                    "required by forward listeners",
+                ));
+            }
+        }
+        // The largest TUN MTU depends on kcp.mtu, which has to make sense first
+        if let (Some(tun), true) = (&self.tun, kcp_valid) {
+            let max_mtu = crate::tun::max_mtu(&self.kcp);
+            if matches!(tun.mtu, Some(mtu) if mtu > max_mtu) {
+                errors.push(ConfigError::new(
+                    "tun.mtu",
+                    format!(
+                        "must be at most {} with kcp.mtu = {}",
+                        max_mtu, self.kcp.mtu
+                    ),
+                ));
+            }
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(ValidationError(errors))
+        }
+    }
+}
+
+impl Config {
+    /// Takes over from `running` the settings that cannot change without a restart, and returns
+    /// the names of those that differ.
+    pub fn keep_restart_only(&mut self, running: &Config) -> Vec<&'static str> {
+        let mut changed = Vec::new();
+        if self.key != running.key {
+            changed.push("key");
+            self.key = running.key;
+        }
+        if self.remote != running.remote {
+            changed.push("remote");
+            self.remote = running.remote;
+        }
+        if self.icmp != running.icmp {
+            changed.push("icmp");
+            self.icmp = running.icmp.clone();
+        }
+        if self.kcp.mtu != running.kcp.mtu {
+            changed.push("kcp.mtu");
+            self.kcp.mtu = running.kcp.mtu;
+        }
+        // The auth and target of a listener can change as long as its socket stays the same
+        let sockets = |config: &Config| -> Vec<_> {
+            config
+                .listen
+                .iter()
+                .map(|listen| (listen.address, listen.protocol, listen.dual_stack))
+                .collect()
+        };
+        if sockets(self) != sockets(running) {
+            changed.push("listen");
+            self.listen = running.listen.clone();
+        }
+        if self.remote_forward != running.remote_forward {
+            changed.push("remote_forward");
+            self.remote_forward = running.remote_forward.clone();
+        }
+        if self.tun != running.tun {
+            changed.push("tun");
+            self.tun = running.tun.clone();
+        }
+        if self.metrics != running.metrics {
+            changed.push("metrics");
+            self.metrics = running.metrics.clone();
+        }
+        if self.log.format != running.log.format {
+            changed.push("log.format");
+            self.log.format = running.log.format;
+        }
+        if self.log.file != running.log.file || self.log.rotation != running.log.rotation {
+            changed.push("log.file");
+            self.log.file = running.log.file.clone();
+            self.log.rotation = running.log.rotation;
+        }
+        if self.log.flame != running.log.flame {
+            changed.push("log.flame");
+            self.log.flame = running.log.flame.clone();
+        }
+        if self.admin != running.admin {
+            changed.push("admin");
+            self.admin = running.admin.clone();
+        }
+        if self.capture != running.capture {
+            changed.push("capture");
+            self.capture = running.capture.clone();
+        }
+        changed
+    }
+}
 
 fn deserialize_key<'de, D: Deserializer<'de>>(d: D) -> Result<Key, D::Error> {
     struct HexKeyVisitor;
@@ -48,25 +263,143 @@ fn deserialize_key<'de, D: Deserializer<'de>>(d: D) -> Result<Key, D::Error> {
         }
 
         fn visit_str<E: Error>(self, v: &str) -> Result<Self::Value, E> {
-            let bytes = hex::decode(v).map_err(E::custom)?;
-            Ok(Key::from_exact_iter(bytes).ok_or_else(|| E::custom("wrong key length"))?)
+            parse_key(v).map_err(E::custom)
         }
     }
     d.deserialize_any(HexKeyVisitor)
 }
 
-pub fn config() -> &'static Config {
-    CONFIG.get().expect("config not initialized")
+/// Parses a key written as 64 hex digits, as in the `key` setting.
+pub fn parse_key(s: &str) -> Result<Key> {
+    let bytes: [u8; 32] = hex::decode(s)
+        .context("key is not a hex string")?
+        .try_into()
+        .map_err(|_| anyhow!("wrong key length"))?;
+    Ok(Key::from(bytes))
 }
 
-pub async fn load_config_from_file(path: impl AsRef<Path>) -> Result<()> {
-    let content = tokio::fs::read_to_string(path)
-        .await
-        .context("loading config")?;
-    let config = toml::from_str(&content).context("parsing config file")?;
-    CONFIG
-        .set(config)
-        .ok()
-        .context("error setting OnceCell for Config")?;
-    Ok(())
+/// Generates a key from the OS random number generator.
+pub fn generate_key() -> Key {
+    let mut key = Key::default();
+    OsRng.fill_bytes(&mut key);
+    key
+}
+
+/// Loads and validates the configuration from a file, its includes and the environment.
+pub async fn load_config_from_file(path: impl AsRef<Path>) -> Result<Config> {
+    let path = path.as_ref().to_owned();
+    let table = task::spawn_blocking(move || source::read(&path)).await??;
+    let config = Config::deserialize(Value::Table(table)).context("parsing config")?;
+    config.validate()?;
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+
+    /// Parses a configuration with `extra` appended to the minimal one.
+    fn parse(extra: &str) -> Config {
+        toml::from_str(&format!("key = \"{}\"\n[kcp]\n[icmp]\n{}", KEY, extra)).unwrap()
+    }
+
+    /// The fields found at fault in `config`.
+    fn invalid(config: Config) -> Vec<String> {
+        match config.validate() {
+            Ok(()) => Vec::new(),
+            Err(ValidationError(errors)) => errors.into_iter().map(|err| err.field).collect(),
+        }
+    }
+
+    #[test]
+    fn valid() {
+        assert!(parse("").validate().is_ok());
+        let server: Config = toml::from_str(&template::server(&generate_key())).unwrap();
+        assert!(server.validate().is_ok());
+        let config = parse(
+            "[session]\nidle_timeout = 600\n[relay]\nidle_timeout = 0\n[udp]\nidle_timeout = 0",
+        );
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn kcp_and_icmp() {
+        let mut config = parse("");
+        config.kcp.interval = 0;
+        config.icmp.send_buffer = 0;
+        assert_eq!(invalid(config), ["kcp.interval", "icmp.send_buffer"]);
+    }
+
+    #[test]
+    fn raw_buffer() {
+        let mut config = parse("");
+        config.icmp.raw_buffer = config.kcp.mtu as usize;
+        assert_eq!(invalid(config), ["icmp.raw_buffer"]);
+    }
+
+    #[test]
+    fn timeouts() {
+        let mut config = parse("");
+        config.session.idle_timeout = MAX_TIMEOUT;
+        config.relay.idle_timeout = MAX_TIMEOUT;
+        assert!(config.validate().is_ok());
+        config.session.idle_timeout = MAX_TIMEOUT + 1;
+        config.relay.idle_timeout = MAX_TIMEOUT + 1;
+        config.relay.half_close_timeout = u64::MAX;
+        config.udp.idle_timeout = u64::MAX;
+        assert_eq!(
+            invalid(config),
+            [
+                "session.idle_timeout",
+                "relay.idle_timeout",
+                "relay.half_close_timeout",
+                "udp.idle_timeout"
+            ]
+        );
+    }
+
+    #[test]
+    fn keepalive() {
+        let mut config = parse("");
+        config.kcp.keepalive = Some(10000);
+        config.session.idle_timeout = 10;
+        assert_eq!(invalid(config), ["session.idle_timeout"]);
+        let mut config = parse("");
+        config.kcp.keepalive = Some(10000);
+        config.session.idle_timeout = 11;
+        assert!(config.validate().is_ok());
+        // Keepalive does not matter to sessions that never time out
+        config.session.idle_timeout = 0;
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn forward_target() {
+        let config = parse(
+            "[[listen]]\naddress = \"127.0.0.1:8080\"\nprotocol = \"forward\"\n\
+             [[listen]]\naddress = \"127.0.0.1:8081\"\nprotocol = \"forward\"\n\
+             target = \"example.com:80\"",
+        );
+        assert_eq!(invalid(config), ["listen[0].target"]);
+    }
+
+    #[test]
+    fn tun_mtu() {
+        let mut config = parse("[tun]\naddress = \"10.0.0.1/24\"");
+        let max_mtu = crate::tun::max_mtu(&config.kcp);
+        config.tun.as_mut().unwrap().mtu = Some(max_mtu);
+        assert!(config.validate().is_ok());
+        config.tun.as_mut().unwrap().mtu = Some(max_mtu + 1);
+        assert_eq!(invalid(config), ["tun.mtu"]);
+    }
+
+    #[test]
+    fn tun_mtu_with_invalid_kcp() {
+        // Not checked against an mtu that makes no sense itself
+        let mut config = parse("[tun]\naddress = \"10.0.0.1/24\"\nmtu = 1400");
+        config.kcp.mtu = 0;
+        assert_eq!(invalid(config), ["kcp.mtu"]);
+    }
 }
@@ -0,0 +1,339 @@
+/*
+Copyright 2021 Chengyuan Ma
+
+Permission is hereby granted, free of charge, to any person obtaining a copy of this software and
+associated documentation files (the "Software"), to deal in the Software without restriction,
+including without limitation the rights to use, copy, modify, merge, publish, distribute, sub-
+-license, and/or sell copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all copies or substantial
+portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING BUT
+NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NON-
+-INFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES
+OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+*/
+
+//! Assembling the TOML table a [Config](super::Config) is deserialized from, out of the config
+//! file, the files it includes, the environment and the key file.
+
+use anyhow::{bail, Context, Result};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use toml::value::{Table, Value};
+
+/// Prefix of environment variables overriding settings.
+const ENV_PREFIX: &str = "EKHO_";
+/// Separator of the sections in the names of environment variables.
+const ENV_SEPARATOR: &str = "__";
+/// Guards against include cycles.
+const MAX_INCLUDE_DEPTH: usize = 8;
+
+/// Reads the config file at `path` with everything it includes, then applies the environment
+/// overrides and reads the key file.
+pub(super) fn read(path: &Path) -> Result<Table> {
+    let mut table = read_file(path, 0)?;
+    apply_env(&mut table, env::vars())?;
+    read_key_file(&mut table)?;
+    Ok(table)
+}
+
+/// Reads a file and the files it includes. Settings in the file override those included, and
+/// later includes override earlier ones.
+fn read_file(path: &Path, depth: usize) -> Result<Table> {
+    if depth > MAX_INCLUDE_DEPTH {
+        bail!("includes nested too deeply (is there a cycle?)");
+    }
+    let content =
+        fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+    let mut table: Table =
+        toml::from_str(&content).with_context(|| format!("parsing {}", path.display()))?;
+    let dir = path.parent().unwrap_or_else(|| Path::new(""));
+    // Relative paths are relative to the file they are written in
+    if let Some(Value::String(key_file)) = table.get_mut("key_file") {
+        *key_file = dir.join(&*key_file).to_string_lossy().into_owned();
+    }
+    let includes = match table.remove("include") {
+        None => Vec::new(),
+        Some(Value::String(include)) => vec![include],
+        Some(Value::Array(includes)) => includes
+            .into_iter()
+            .map(|include| match include {
+                Value::String(include) => Ok(include),
+                _ => bail!("include: expected a path or an array of paths"),
+            })
+            .collect::<Result<_>>()?,
+        Some(_) => bail!("include: expected a path or an array of paths"),
+    };
+    let mut merged = Table::new();
+    for include in includes {
+        let include: PathBuf = dir.join(include);
+        let included = read_file(&include, depth + 1)
+            .with_context(|| format!("included from {}", path.display()))?;
+        merge(&mut merged, included);
+    }
+    merge(&mut merged, table);
+    Ok(merged)
+}
+
+/// Merges `from` into `into`, recursing into tables. Other values, arrays included, are replaced.
+fn merge(into: &mut Table, from: Table) {
+    for (key, value) in from {
+        match (into.get_mut(&key), value) {
+            (Some(Value::Table(into)), Value::Table(from)) => merge(into, from),
+            (_, value) => {
+                into.insert(key, value);
+            }
+        }
+    }
+}
+
+/// Applies `EKHO_SECTION__FIELD=value` overrides, e.g. `EKHO_KCP__MTU=1200` sets `kcp.mtu`. Values
+/// are read as TOML if they can be, and as strings otherwise.
+fn apply_env(table: &mut Table, vars: impl Iterator<Item = (String, String)>) -> Result<()> {
+    for (name, raw) in vars {
+        let path = match name.strip_prefix(ENV_PREFIX) {
+            Some(path) if !path.is_empty() => path.to_lowercase(),
+            _ => continue,
+        };
+        let value = toml::from_str::<Table>(&format!("value = {}", raw))
+            .ok()
+            .and_then(|mut table| table.remove("value"))
+            .unwrap_or(Value::String(raw));
+        // A key given in the environment replaces one given the other way in the file
+        match path.as_str() {
+            "key" => table.remove("key_file"),
+            "key_file" => table.remove("key"),
+            _ => None,
+        };
+        let mut sections: Vec<_> = path.split(ENV_SEPARATOR).collect();
+        let field = sections.pop().unwrap();
+        let mut table = &mut *table;
+        for section in sections {
+            table = match table
+                .entry(section)
+                .or_insert_with(|| Value::Table(Table::new()))
+            {
+                Value::Table(table) => table,
+                _ => bail!("{}: {} is not a section", name, section),
+            };
+        }
+        table.insert(field.into(), value);
+    }
+    Ok(())
+}
+
+/// Replaces `key_file` with the `key` read from it.
+fn read_key_file(table: &mut Table) -> Result<()> {
+    let key_file = match table.remove("key_file") {
+        None => return Ok(()),
+        Some(Value::String(key_file)) => key_file,
+        Some(_) => bail!("key_file: expected a path"),
+    };
+    if table.contains_key("key") {
+        bail!("key and key_file are both set");
+    }
+    let key = fs::read_to_string(&key_file).with_context(|| format!("reading {}", key_file))?;
+    let key = key.trim();
+    super::parse_key(key).with_context(|| format!("reading {}", key_file))?;
+    table.insert("key".into(), Value::String(key.into()));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use serde::Deserialize;
+
+    const KEY: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+
+    /// A scratch directory, removed when dropped.
+    struct Dir(PathBuf);
+
+    impl Dir {
+        fn new(name: &str) -> Dir {
+            let path = env::temp_dir().join(format!("ekho-source-{}-{}", std::process::id(), name));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(&path).unwrap();
+            Dir(path)
+        }
+
+        /// Writes `content` to `name` in the directory, returning its path.
+        fn write(&self, name: &str, content: &str) -> PathBuf {
+            let path = self.0.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, content).unwrap();
+            path
+        }
+    }
+
+    impl Drop for Dir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn vars(vars: &[(&str, &str)]) -> impl Iterator<Item = (String, String)> {
+        vars.iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    fn table(content: &str) -> Table {
+        toml::from_str(content).unwrap()
+    }
+
+    fn config(table: Table) -> Result<Config> {
+        Ok(Config::deserialize(Value::Table(table))?)
+    }
+
+    #[test]
+    fn env_overrides_file() {
+        let mut table = table(&format!(
+            "key = \"{}\"\n[kcp]\nmtu = 536\ninterval = 40\n[icmp]",
+            KEY
+        ));
+        apply_env(
+            &mut table,
+            vars(&[
+                ("EKHO_KCP__MTU", "1200"),
+                ("EKHO_LOG__LEVEL", "debug"),
+                ("EKHO_REMOTE", "{ ip = \"192.0.2.1\", id = 1 }"),
+                ("PATH", "/bin"),
+                ("EKHO_", "ignored"),
+            ]),
+        )
+        .unwrap();
+        let config = config(table).unwrap();
+        assert_eq!(config.kcp.mtu, 1200);
+        assert_eq!(config.kcp.interval, 40);
+        assert_eq!(config.log.level.to_string(), "DEBUG");
+        assert_eq!(config.remote.unwrap().id, 1);
+    }
+
+    #[test]
+    fn env_malformed() {
+        let base = format!("key = \"{}\"\n[kcp]\nmtu = 536\n[icmp]", KEY);
+        // Taken as a string, which is not a number
+        let mut table = table(&base);
+        apply_env(&mut table, vars(&[("EKHO_KCP__MTU", "12OO")])).unwrap();
+        assert_eq!(table["kcp"]["mtu"], Value::String("12OO".into()));
+        assert!(config(table).is_err());
+
+        let mut table = self::table(&base);
+        let err = apply_env(&mut table, vars(&[("EKHO_KCP__MTU__X", "1")])).unwrap_err();
+        assert_eq!(err.to_string(), "EKHO_KCP__MTU__X: mtu is not a section");
+        let mut table = self::table(&base);
+        apply_env(&mut table, vars(&[("EKHO_KEY__X", "1")])).unwrap_err();
+    }
+
+    #[test]
+    fn env_key_replaces_key_file() {
+        let mut table = table("key_file = \"ekho.key\"");
+        apply_env(&mut table, vars(&[("EKHO_KEY", KEY)])).unwrap();
+        assert!(!table.contains_key("key_file"));
+        let mut table = self::table(&format!("key = \"{}\"", KEY));
+        apply_env(&mut table, vars(&[("EKHO_KEY_FILE", "ekho.key")])).unwrap();
+        assert!(!table.contains_key("key"));
+    }
+
+    #[test]
+    fn includes() {
+        let dir = Dir::new("includes");
+        dir.write("common/kcp.toml", "[kcp]\nmtu = 1200\ninterval = 20");
+        dir.write(
+            "common/icmp.toml",
+            "include = \"../icmp/buffers.toml\"\n[icmp]",
+        );
+        dir.write("icmp/buffers.toml", "[icmp]\nsend_buffer = 16");
+        dir.write("common/ekho.key", KEY);
+        let path = dir.write(
+            "ekho.toml",
+            "include = [\"common/kcp.toml\", \"common/icmp.toml\"]\n\
+             key_file = \"common/ekho.key\"\n[kcp]\ninterval = 10",
+        );
+        let config = config(read(&path).unwrap()).unwrap();
+        assert_eq!(config.kcp.mtu, 1200);
+        // The including file wins
+        assert_eq!(config.kcp.interval, 10);
+        assert_eq!(config.icmp.send_buffer, 16);
+        assert_eq!(hex::encode(config.key), KEY);
+    }
+
+    #[test]
+    fn include_cycle() {
+        let dir = Dir::new("include-cycle");
+        dir.write("a.toml", "include = \"b.toml\"");
+        let path = dir.write("b.toml", "include = \"a.toml\"");
+        let err = read_file(&path, 0).unwrap_err();
+        assert!(
+            format!("{:#}", err).contains("nested too deeply"),
+            "{:#}",
+            err
+        );
+
+        // Deep but finite nesting is fine
+        for i in 0..MAX_INCLUDE_DEPTH {
+            dir.write(
+                &format!("{}.toml", i),
+                &format!("include = \"{}.toml\"", i + 1),
+            );
+        }
+        dir.write(&format!("{}.toml", MAX_INCLUDE_DEPTH), "[kcp]");
+        assert!(read_file(&dir.0.join("0.toml"), 0)
+            .unwrap()
+            .contains_key("kcp"));
+        dir.write(
+            &format!("{}.toml", MAX_INCLUDE_DEPTH),
+            "include = \"last.toml\"",
+        );
+        dir.write("last.toml", "[kcp]");
+        read_file(&dir.0.join("0.toml"), 0).unwrap_err();
+    }
+
+    #[test]
+    fn include_malformed() {
+        let dir = Dir::new("include-malformed");
+        let path = dir.write("ekho.toml", "include = 1");
+        read_file(&path, 0).unwrap_err();
+        let path = dir.write("ekho.toml", "include = \"missing.toml\"");
+        let err = read_file(&path, 0).unwrap_err();
+        assert!(format!("{:#}", err).contains("missing.toml"), "{:#}", err);
+    }
+
+    #[test]
+    fn key_and_key_file() {
+        let mut table = table(&format!("key = \"{}\"\nkey_file = \"ekho.key\"", KEY));
+        let err = read_key_file(&mut table).unwrap_err();
+        assert_eq!(err.to_string(), "key and key_file are both set");
+    }
+
+    #[test]
+    fn key_file_length() {
+        let dir = Dir::new("key-file-length");
+        let path = dir.write("ekho.key", &format!("{}\n", KEY));
+        let mut table = Table::new();
+        table.insert(
+            "key_file".into(),
+            path.to_string_lossy().into_owned().into(),
+        );
+        read_key_file(&mut table).unwrap();
+        assert_eq!(table["key"], Value::String(KEY.into()));
+
+        for key in [&KEY[..62], &format!("{}00", KEY), "", "not hex"] {
+            dir.write("ekho.key", key);
+            let mut table = Table::new();
+            table.insert(
+                "key_file".into(),
+                path.to_string_lossy().into_owned().into(),
+            );
+            read_key_file(&mut table).unwrap_err();
+        }
+    }
+}
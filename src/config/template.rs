@@ -0,0 +1,114 @@
+/*
+Copyright 2021 Chengyuan Ma
+
+Permission is hereby granted, free of charge, to any person obtaining a copy of this software and
+associated documentation files (the "Software"), to deal in the Software without restriction,
+including without limitation the rights to use, copy, modify, merge, publish, distribute, sub-
+-license, and/or sell copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all copies or substantial
+portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING BUT
+NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NON-
+-INFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES
+OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+*/
+
+//! Commented config files for a new server and its clients, as written by `ekho init`.
+
+use crate::icmp::Endpoint;
+use chacha20poly1305::Key;
+
+/// The `[kcp]` and `[icmp]` sections, shared by both ends so that they start out matching.
+const TRANSPORT: &str = r#"# KCP tuning; all times are in milliseconds. Both ends should use the same values.
+[kcp]
+# Payload size of each ICMP packet. Raise it (e.g. to 1200) on paths known to carry larger
+# packets, but keep it below the path MTU minus the IP and ICMP headers.
+mtu = 536
+# How often the control block is flushed. Lower values cut latency at some CPU cost.
+interval = 40
+# Send and receive windows, in segments.
+send_wnd = 1024
+recv_wnd = 1024
+# Retransmission timeouts.
+rto_default = 200
+rto_min = 100
+rto_max = 6000
+# Retransmit a segment once this many later segments have been acknowledged.
+# fast_resend_thres = 2
+# Probe an idle link this often, and give up on it after keepalive_timeout.
+# keepalive = 10000
+# keepalive_timeout = 60000
+
+# Buffers of the ICMP transport, in packets (send_buffer, recv_buffer) and bytes (raw_buffer).
+[icmp]
+send_buffer = 1024
+recv_buffer = 1024
+raw_buffer = 8192
+
+[session]
+# Tear down sessions without any data for this long, in seconds (0 to never time out). Keepalive
+# probes count as activity, so turn keepalive on above to keep idle sessions with a live peer.
+idle_timeout = 0
+
+[log]
+# One of error, warn, info, debug and trace.
+level = "info"
+# Levels of particular modules, e.g. "ekho::session=debug,ekho_kcp=warn".
+# filter = ""
+# compact, full or json (an object per line, for log shippers).
+# format = "compact"
+# Log to this file, starting a new one every day, instead of stdout.
+# file = "/var/log/ekho/ekho.log"
+"#;
+
+/// A server config using `key`.
+pub fn server(key: &Key) -> String {
+    format!(
+        r#"# Ekho server. Run with `ekho server -c <this file>` as root or with CAP_NET_RAW.
+#
+# The server answers echo requests itself, so the kernel's own replies are best turned off:
+#   sysctl -w net.ipv4.icmp_echo_ignore_all=1
+
+# Shared by the server and all its clients. Keep it secret; `ekho genkey` makes a new one.
+key = "{key}"
+
+{transport}"#,
+        key = hex::encode(key),
+        transport = TRANSPORT,
+    )
+}
+
+/// A client config using `key` to connect to `remote`.
+pub fn client(key: &Key, remote: Endpoint) -> String {
+    format!(
+        r#"# Ekho client. Run with `ekho client -c <this file>` as root or with CAP_NET_RAW.
+
+# Must be the same as the server's.
+key = "{key}"
+
+# The server's IP, and the echo identifier this client sends with. Clients sharing a server
+# should use different identifiers.
+remote = {{ ip = "{ip}", id = {id} }}
+
+# A SOCKS5 and HTTP proxy for local applications. Add more [[listen]] sections for other
+# protocols, see the documentation of ekho::listen.
+[[listen]]
+address = "127.0.0.1:1080"
+protocol = "mixed"
+
+# Everything goes through the server by default. See the documentation of ekho::route for
+# rules sending some destinations directly instead.
+[routing]
+default = "proxy"
+
+{transport}"#,
+        key = hex::encode(key),
+        ip = remote.ip,
+        id = remote.id,
+        transport = TRANSPORT,
+    )
+}
@@ -0,0 +1,246 @@
+/*
+Copyright 2021 Chengyuan Ma
+
+Permission is hereby granted, free of charge, to any person obtaining a copy of this software and
+associated documentation files (the "Software"), to deal in the Software without restriction,
+including without limitation the rights to use, copy, modify, merge, publish, distribute, sub-
+-license, and/or sell copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all copies or substantial
+portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING BUT
+NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NON-
+-INFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES
+OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+*/
+
+//! The Ekho runtime.
+//!
+//! An [Ekho] instance owns everything a peer runs on: its configuration, the ICMP transport, the
+//! table of live sessions, the cipher, and the TUN device and the packet capture if any. Instances
+//! share nothing, so several of them (e.g. a client and a server) can live in the same process.
+//!
+//! The configuration can be [reloaded](Ekho::reload) while running. Settings that the transport,
+//! the cipher, the listeners or the TUN device were set up with keep their running values until
+//! a restart; everything else applies to new sessions, connections and requests.
+
+use crate::capture::Capture;
+use crate::config::{Config, ValidationError};
+use crate::icmp::{Endpoint, Transport};
+use crate::metrics::Metrics;
+use crate::session::{self, Control, Session, SessionInfo};
+use crate::tun::Tunnel;
+use anyhow::Result;
+use chacha20poly1305::aead::NewAead;
+use chacha20poly1305::ChaCha20Poly1305;
+use dashmap::DashMap;
+use parking_lot::{Mutex as SyncMutex, RwLock};
+use rustc_hash::FxHasher;
+use serde::Serialize;
+use std::fmt;
+use std::hash::BuildHasherDefault;
+use std::sync::{Arc, Weak};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::sync::Mutex;
+use tokio::task::{self, JoinHandle};
+
+pub(crate) type Controls = DashMap<(Endpoint, u32), Weak<Control>, BuildHasherDefault<FxHasher>>;
+
+/// A handle to an Ekho instance. Handles are cheap to clone and all refer to the same instance.
+#[derive(Clone)]
+pub struct Ekho(Arc<Inner>);
+
+struct Inner {
+    config: RwLock<Arc<Config>>,
+    transport: Transport,
+    metrics: Arc<Metrics>,
+    controls: Controls,
+    cipher: ChaCha20Poly1305,
+    incoming: (UnboundedSender<Session>, Mutex<UnboundedReceiver<Session>>),
+    tun: Option<Tunnel>,
+    capture: Option<Capture>,
+    dispatcher: SyncMutex<Option<JoinHandle<()>>>,
+}
+
+impl Ekho {
+    /// Opens the ICMP transport (and the TUN device if configured) and starts dispatching incoming
+    /// packets to sessions.
+    pub async fn bind(config: Config) -> Result<Ekho> {
+        let metrics = Arc::new(Metrics::default());
+        let transport = Transport::open(&config, metrics.clone())?;
+        Ekho::start(config, transport, metrics).await
+    }
+
+    /// Like [bind](Ekho::bind), but on `transport` instead of a new ICMP socket, e.g. one end of a
+    /// [pair](Transport::pair).
+    pub async fn with_transport(config: Config, transport: Transport) -> Result<Ekho> {
+        Ekho::start(config, transport, Arc::new(Metrics::default())).await
+    }
+
+    async fn start(config: Config, transport: Transport, metrics: Arc<Metrics>) -> Result<Ekho> {
+        let tun = match &config.tun {
+            Some(tun) => Some(tun.open(&config.kcp).await?),
+            None => None,
+        };
+        let capture = match &config.capture.file {
+            Some(path) => Some(Capture::create(path)?),
+            None => None,
+        };
+        let (tx, rx) = unbounded_channel();
+        let ekho = Ekho(Arc::new(Inner {
+            cipher: ChaCha20Poly1305::new(&config.key),
+            config: RwLock::new(Arc::new(config)),
+            transport,
+            metrics,
+            controls: Default::default(),
+            incoming: (tx, Mutex::new(rx)),
+            tun,
+            capture,
+            dispatcher: SyncMutex::new(None),
+        }));
+        let dispatcher = task::spawn(session::dispatch_loop(ekho.clone()));
+        *ekho.0.dispatcher.lock() = Some(dispatcher);
+        Ok(ekho)
+    }
+
+    /// The configuration in effect. Reloading does not affect configurations already obtained.
+    pub fn config(&self) -> Arc<Config> {
+        self.0.config.read().clone()
+    }
+
+    /// Validates `config` and puts it into effect, except for the settings that need a restart.
+    pub fn reload(&self, mut config: Config) -> Result<Reload, ValidationError> {
+        config.validate()?;
+        let mut running = self.0.config.write();
+        let restart_required = config.keep_restart_only(&running);
+        crate::log::reload(&config.log);
+        *running = Arc::new(config);
+        Ok(Reload { restart_required })
+    }
+
+    /// Opens a new session to `peer`.
+    pub fn connect(&self, peer: Endpoint) -> Session {
+        Session::connect(self.clone(), peer)
+    }
+
+    /// Waits for a session opened by a peer.
+    pub async fn accept(&self) -> Session {
+        self.0.incoming.1.lock().await.recv().await.unwrap()
+    }
+
+    /// Lists the live sessions, oldest first.
+    pub async fn sessions(&self) -> Vec<SessionInfo> {
+        let controls: Vec<_> = self
+            .0
+            .controls
+            .iter()
+            .filter_map(|entry| Some((*entry.key(), entry.value().upgrade()?)))
+            .collect();
+        let mut sessions = Vec::with_capacity(controls.len());
+        for ((peer, conv), control) in controls {
+            let stats = session::lock(&control).await.stats();
+            sessions.push(SessionInfo {
+                peer,
+                conv,
+                age: control.2.elapsed().as_secs_f64(),
+                stats,
+            });
+        }
+        sessions.sort_by(|a, b| b.age.partial_cmp(&a.age).unwrap());
+        sessions
+    }
+
+    /// Resets the session with `peer` and `conv`, if it is still live. Returns whether it was.
+    pub async fn kill(&self, peer: Endpoint, conv: u32) -> bool {
+        let control = self
+            .0
+            .controls
+            .get(&(peer, conv))
+            .and_then(|weak| weak.upgrade());
+        match control {
+            Some(control) => {
+                session::lock(&control).await.reset();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Stops taking incoming packets and resets every live session. Sessions opened afterwards go
+    /// nowhere; the transport is closed once the last handle is dropped.
+    pub async fn shutdown(&self) {
+        if let Some(dispatcher) = self.0.dispatcher.lock().take() {
+            dispatcher.abort();
+        }
+        let controls: Vec<_> = self
+            .0
+            .controls
+            .iter()
+            .filter_map(|entry| entry.value().upgrade())
+            .collect();
+        for control in controls {
+            session::lock(&control).await.reset();
+        }
+    }
+
+    pub fn metrics(&self) -> &Metrics {
+        &self.0.metrics
+    }
+
+    pub(crate) fn transport(&self) -> &Transport {
+        &self.0.transport
+    }
+
+    pub(crate) fn controls(&self) -> &Controls {
+        &self.0.controls
+    }
+
+    pub(crate) fn cipher(&self) -> &ChaCha20Poly1305 {
+        &self.0.cipher
+    }
+
+    pub(crate) fn incoming(&self) -> &UnboundedSender<Session> {
+        &self.0.incoming.0
+    }
+
+    pub(crate) fn tun(&self) -> Option<&Tunnel> {
+        self.0.tun.as_ref()
+    }
+
+    pub(crate) fn capture(&self) -> Option<&Capture> {
+        self.0.capture.as_ref()
+    }
+}
+
+/// The outcome of [Ekho::reload].
+#[derive(Debug, Serialize)]
+pub struct Reload {
+    /// Settings that have been changed but keep their running values until a restart.
+    pub restart_required: Vec<&'static str>,
+}
+
+impl fmt::Display for Reload {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "configuration reloaded")?;
+        if !self.restart_required.is_empty() {
+            write!(
+                f,
+                "; changes to {} take effect after a restart",
+                self.restart_required.join(", ")
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Ekho {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.config().remote {
+            Some(remote) => write!(f, "client of {}", remote),
+            None => write!(f, "server"),
+        }
+    }
+}
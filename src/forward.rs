@@ -0,0 +1,221 @@
+/*
+Copyright 2021 Chengyuan Ma
+
+Permission is hereby granted, free of charge, to any person obtaining a copy of this software and
+associated documentation files (the "Software"), to deal in the Software without restriction,
+including without limitation the rights to use, copy, modify, merge, publish, distribute, sub-
+-license, and/or sell copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all copies or substantial
+portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING BUT
+NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NON-
+-INFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES
+OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+*/
+
+//! Remote port forwarding (like `ssh -R`).
+//!
+//! For every `[[remote_forward]]` entry, the client keeps a registration session open, asking the
+//! server to listen on `listen`. Each connection accepted there makes the server open a new session
+//! back to the client with a CONNECT request whose DST is `listen`; the client then connects to
+//! the matching `target` and relays.
+//!
+//! ```toml
+//! [[remote_forward]]
+//! listen = "0.0.0.0:8080"
+//! target = "127.0.0.1:80"
+//! ```
+//!
+//! Local forwards are `forward` listeners, see [crate::listen].
+
+use crate::ekho::Ekho;
+use crate::icmp::Endpoint;
+use crate::relay::relay_kcp;
+use crate::session::Session;
+use crate::socks5::{Socks5Command, Socks5Error, Socks5Reply, Socks5Request, Socks5SocketAddr};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::select;
+use tokio::task;
+use tokio::time::{sleep, Duration};
+use tracing::{debug, error, info, instrument, warn};
+
+/// Delay before registering a remote forward again after it fails or the server drops it.
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// A remote forward.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Config {
+    /// The address the server listens on.
+    pub listen: SocketAddr,
+    /// Where the client connects for every connection accepted by the server.
+    pub target: Socks5SocketAddr,
+}
+
+/// Keeps a remote forward registered with the server.
+#[instrument]
+pub async fn register(ekho: Ekho, forward: Config) {
+    let request = Socks5Request {
+        cmd: Socks5Command::RemoteForward,
+        dst: forward.listen.into(),
+    };
+    loop {
+        let session = ekho.connect(ekho.config().remote.unwrap());
+        session.send(&request.marshal()).await;
+        match Socks5Reply::parse(&session.recv().await) {
+            Ok(Socks5Reply::Success { bnd }) => {
+                info!("server listening on {} for {}", bnd, forward.target);
+                // The server never sends anything else; this returns once it drops the forward.
+                let _ = session.recv().await;
+                warn!("remote forward on {} dropped by server", forward.listen);
+            }
+            Ok(Socks5Reply::Error(err)) => {
+                error!(
+                    "server refused remote forward on {}: {:?}",
+                    forward.listen, err
+                )
+            }
+            Err(err) => error!("invalid reply for remote forward: {}", err),
+        }
+        session.close().await;
+        sleep(RETRY_INTERVAL).await;
+    }
+}
+
+/// Handles a session opened by the server for a connection accepted on a remote forward.
+async fn handle_incoming(session: Session) -> Result<()> {
+    let request = Socks5Request::parse(&session.recv().await)?;
+    debug!("{:?}", request);
+    let config = session.ekho().config();
+    let forward = config
+        .remote_forward
+        .iter()
+        .find(|forward| Socks5SocketAddr::from(forward.listen) == request.dst);
+    let forward = match (request.cmd, forward) {
+        (Socks5Command::Connect, Some(forward)) => forward,
+        _ => {
+            warn!("unexpected request from server: {:?}", request);
+            session
+                .send(&Socks5Reply::Error(Socks5Error::ConnectionNotAllowed).marshal())
+                .await;
+            session.close().await;
+            return Ok(());
+        }
+    };
+    match forward.target.connect().await {
+        Ok(local) => {
+            session
+                .send(
+                    &Socks5Reply::Success {
+                        bnd: local.local_addr()?.into(),
+                    }
+                    .marshal(),
+                )
+                .await;
+            relay_kcp(local, session).await?;
+        }
+        Err(err) => {
+            error!("error while connecting to {}: {}", forward.target, err);
+            session
+                .send(&Socks5Reply::Error(err.into()).marshal())
+                .await;
+            session.close().await;
+        }
+    }
+    Ok(())
+}
+
+/// Accepts the sessions the server opens for remote forwards.
+#[instrument]
+pub async fn run_incoming(ekho: Ekho) {
+    loop {
+        let session = ekho.accept().await;
+        if session.peer() != ekho.config().remote.unwrap() {
+            warn!("ignoring session from unknown peer {:?}", session);
+            continue;
+        }
+        task::spawn(async move {
+            if let Err(err) = handle_incoming(session).await {
+                error!("{}", err);
+            }
+        });
+    }
+}
+
+/// Relays a connection accepted on a remote forward back to the client.
+async fn forward_back(ekho: Ekho, stream: TcpStream, peer: Endpoint, listen: Socks5SocketAddr) {
+    let session = ekho.connect(peer);
+    let request = Socks5Request {
+        cmd: Socks5Command::Connect,
+        dst: listen,
+    };
+    session.send(&request.marshal()).await;
+    let res = match Socks5Reply::parse(&session.recv().await) {
+        Ok(Socks5Reply::Success { .. }) => relay_kcp(stream, session).await,
+        reply => {
+            debug!("client refused forwarded connection: {:?}", reply);
+            session.close().await;
+            Ok(())
+        }
+    };
+    if let Err(err) = res {
+        error!("{}", err);
+    }
+}
+
+/// Serves a remote forward on the server for as long as the registration session lives.
+pub async fn serve_remote_forward(session: Session, request: Socks5Request) -> Result<()> {
+    let listener = match request.dst.resolve().await {
+        Ok(addr) => TcpListener::bind(addr).await,
+        Err(err) => Err(err),
+    };
+    let listener = match listener {
+        Ok(listener) => listener,
+        Err(err) => {
+            error!("error while listening on {}: {}", request.dst, err);
+            session
+                .send(&Socks5Reply::Error(err.into()).marshal())
+                .await;
+            session.close().await;
+            return Ok(());
+        }
+    };
+    session
+        .send(
+            &Socks5Reply::Success {
+                bnd: listener.local_addr()?.into(),
+            }
+            .marshal(),
+        )
+        .await;
+    let peer = session.peer();
+    let accept_loop = async {
+        loop {
+            match listener.accept().await {
+                Ok((stream, from)) => {
+                    debug!("forwarding {} from {} back to {}", request.dst, from, peer);
+                    task::spawn(forward_back(
+                        session.ekho().clone(),
+                        stream,
+                        peer,
+                        request.dst.clone(),
+                    ));
+                }
+                Err(err) => error!("error accepting connection on {}: {}", request.dst, err),
+            }
+        }
+    };
+    select! {
+        _ = accept_loop => {}
+        _ = session.recv() => {}
+    }
+    drop(listener);
+    session.close().await;
+    Ok(())
+}
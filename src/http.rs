@@ -0,0 +1,399 @@
+/*
+Copyright 2021 Chengyuan Ma
+
+Permission is hereby granted, free of charge, to any person obtaining a copy of this software and
+associated documentation files (the "Software"), to deal in the Software without restriction,
+including without limitation the rights to use, copy, modify, merge, publish, distribute, sub-
+-license, and/or sell copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all copies or substantial
+portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING BUT
+NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NON-
+-INFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES
+OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+*/
+
+//! HTTP proxy front end.
+//!
+//! Both `CONNECT host:port` tunnels and plain requests with an absolute URI are supported. Either
+//! way the destination goes through the same path as a SOCKS5 CONNECT request. Plain requests are
+//! rewritten into origin form and sent with `Connection: close`, so every proxied connection
+//! carries exactly one request.
+
+use crate::client::connect;
+use crate::ekho::Ekho;
+use crate::listen::Auth;
+use crate::socks5::{Socks5Error, Socks5Reply, Socks5SocketAddr};
+use anyhow::{bail, Context, Result};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tracing::{debug, instrument};
+
+/// Upper bound for the size of a request head.
+const MAX_HEAD_LEN: usize = 8192;
+
+/// Headers that only concern the hop between the client and us.
+const HOP_BY_HOP_HEADERS: [&str; 4] = [
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "proxy-authorization",
+];
+
+#[derive(Debug)]
+struct RequestHead {
+    method: String,
+    target: String,
+    version: String,
+    headers: Vec<(String, String)>,
+}
+
+impl RequestHead {
+    /// Parses a request head, excluding the terminating empty line.
+    fn parse(buf: &[u8]) -> Option<Self> {
+        let head = std::str::from_utf8(buf).ok()?;
+        let mut lines = head.split("\r\n");
+        let mut request_line = lines.next()?.split(' ');
+        let method = request_line.next()?.into();
+        let target = request_line.next()?.into();
+        let version = request_line.next()?.into();
+        if request_line.next().is_some() {
+            return None;
+        }
+        let headers = lines
+            .map(|line| {
+                let (name, value) = line.split_once(':')?;
+                Some((name.trim().into(), value.trim().into()))
+            })
+            .collect::<Option<_>>()?;
+        Some(RequestHead {
+            method,
+            target,
+            version,
+            headers,
+        })
+    }
+
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn marshal(&self) -> Vec<u8> {
+        let mut ret = format!("{} {} {}\r\n", self.method, self.target, self.version);
+        for (name, value) in &self.headers {
+            ret.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        ret.push_str("\r\n");
+        ret.into_bytes()
+    }
+}
+
+/// Reads a request head from `local`, returning it along with whatever was read past its end.
+async fn read_head(local: &mut TcpStream) -> Result<Option<(RequestHead, Vec<u8>)>> {
+    let mut buf = Vec::with_capacity(1024);
+    loop {
+        if let Some(pos) = buf.windows(4).position(|window| window == b"\r\n\r\n") {
+            let rest = buf.split_off(pos + 4);
+            return Ok(RequestHead::parse(&buf[..pos]).map(|head| (head, rest)));
+        }
+        if buf.len() >= MAX_HEAD_LEN {
+            return Ok(None);
+        }
+        let mut chunk = [0; 1024];
+        let len = local
+            .read(&mut chunk)
+            .await
+            .context("reading HTTP request head")?;
+        if len == 0 {
+            bail!("connection closed before the end of HTTP request head");
+        }
+        buf.extend_from_slice(&chunk[..len]);
+    }
+}
+
+/// Splits an absolute `http://` URI into its authority and its origin-form path.
+fn split_absolute_uri(uri: &str) -> Option<(Socks5SocketAddr, String)> {
+    let rest = uri
+        .get(..7)
+        .filter(|scheme| scheme.eq_ignore_ascii_case("http://"))
+        .map(|_| &uri[7..])?;
+    let (authority, path) = match rest.find('/') {
+        Some(pos) => rest.split_at(pos),
+        None => (rest, "/"),
+    };
+    Some((parse_authority(authority, 80)?, path.into()))
+}
+
+/// Parses `host[:port]`, falling back to `default_port`.
+fn parse_authority(authority: &str, default_port: u16) -> Option<Socks5SocketAddr> {
+    authority
+        .parse()
+        .or_else(|_| format!("{}:{}", authority, default_port).parse())
+        .ok()
+}
+
+/// Maps a SOCKS5 reply to the status line sent back to HTTP clients.
+fn status_line(reply: &Socks5Reply) -> &'static str {
+    match reply {
+        Socks5Reply::Success { .. } => "200 Connection established",
+        Socks5Reply::Error(Socks5Error::ConnectionNotAllowed) => "403 Forbidden",
+        Socks5Reply::Error(Socks5Error::TtlExpired) => "504 Gateway Timeout",
+        Socks5Reply::Error(_) => "502 Bad Gateway",
+    }
+}
+
+/// Formats a response without a body. Successful ones only ever answer CONNECT, after which the
+/// connection is a tunnel, so they must not have a `Content-Length` (RFC 7231, section 4.3.6).
+fn response(version: &str, status: &str) -> String {
+    if status.starts_with('2') {
+        format!("{} {}\r\n\r\n", version, status)
+    } else {
+        format!("{} {}\r\nContent-Length: 0\r\n\r\n", version, status)
+    }
+}
+
+async fn respond(local: &mut TcpStream, version: &str, status: &str) -> Result<()> {
+    local
+        .write_all(response(version, status).as_bytes())
+        .await
+        .context("replying HTTP client")
+}
+
+/// Standard base64 encoding with padding.
+fn base64_encode(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut ret = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bits = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |acc, (i, &byte)| acc | (byte as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                ret.push(ALPHABET[(bits >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                ret.push('=');
+            }
+        }
+    }
+    ret
+}
+
+/// Checks the `Proxy-Authorization` header against the basic auth credentials.
+fn authorized(head: &RequestHead, auth: &Auth) -> bool {
+    let expected = base64_encode(format!("{}:{}", auth.username, auth.password).as_bytes());
+    head.header("proxy-authorization")
+        .and_then(|value| value.split_once(' '))
+        .is_some_and(|(scheme, credentials)| {
+            scheme.eq_ignore_ascii_case("basic") && credentials.trim() == expected
+        })
+}
+
+#[instrument(skip(ekho, local, auth), fields(local = "local.peer_addr().unwrap()"))]
+pub async fn handle_http(ekho: &Ekho, mut local: TcpStream, auth: Option<&Auth>) -> Result<()> {
+    let (mut head, rest) = match read_head(&mut local).await? {
+        Some(parsed) => parsed,
+        None => return respond(&mut local, "HTTP/1.1", "400 Bad Request").await,
+    };
+    debug!("{} {}", head.method, head.target);
+    if !auth.is_none_or(|auth| authorized(&head, auth)) {
+        local
+            .write_all(
+                format!(
+                    "{} 407 Proxy Authentication Required\r\n\
+                     Proxy-Authenticate: Basic realm=\"ekho\"\r\n\
+                     Content-Length: 0\r\n\r\n",
+                    head.version
+                )
+                .as_bytes(),
+            )
+            .await
+            .context("replying HTTP client (authentication required)")?;
+        return Ok(());
+    }
+    if head.method.eq_ignore_ascii_case("CONNECT") {
+        let dst = match parse_authority(&head.target, 443) {
+            Some(dst) => dst,
+            None => return respond(&mut local, &head.version, "400 Bad Request").await,
+        };
+        let (reply, outbound) = connect(ekho, &dst).await?;
+        respond(&mut local, &head.version, status_line(&reply)).await?;
+        if let Some(mut outbound) = outbound {
+            outbound.write_all(&rest).await?;
+            outbound.relay(ekho, local).await?;
+        }
+    } else {
+        let (dst, path) = match split_absolute_uri(&head.target) {
+            Some(split) => split,
+            None => return respond(&mut local, &head.version, "400 Bad Request").await,
+        };
+        let (reply, outbound) = connect(ekho, &dst).await?;
+        let mut outbound = match outbound {
+            Some(outbound) => outbound,
+            None => return respond(&mut local, &head.version, status_line(&reply)).await,
+        };
+        if head.header("host").is_none() {
+            head.headers.push(("Host".into(), dst.to_string()));
+        }
+        head.target = path;
+        head.headers
+            .retain(|(name, _)| !HOP_BY_HOP_HEADERS.contains(&name.to_lowercase().as_str()));
+        head.headers.push(("Connection".into(), "close".into()));
+        outbound.write_all(&head.marshal()).await?;
+        outbound.write_all(&rest).await?;
+        outbound.relay(ekho, local).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::socks5::Socks5Addr;
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+    fn hostname(host: &str, port: u16) -> Socks5SocketAddr {
+        Socks5SocketAddr {
+            addr: Socks5Addr::Hostname(host.into()),
+            port,
+        }
+    }
+
+    fn ip(ip: IpAddr, port: u16) -> Socks5SocketAddr {
+        Socks5SocketAddr {
+            addr: Socks5Addr::Ip(ip),
+            port,
+        }
+    }
+
+    #[test]
+    fn base64() {
+        assert_eq!(base64_encode(b""), "");
+        assert_eq!(base64_encode(b"f"), "Zg==");
+        assert_eq!(base64_encode(b"fo"), "Zm8=");
+        assert_eq!(base64_encode(b"foo"), "Zm9v");
+        assert_eq!(base64_encode(b"foob"), "Zm9vYg==");
+        assert_eq!(base64_encode(b"fooba"), "Zm9vYmE=");
+        assert_eq!(base64_encode(b"foobar"), "Zm9vYmFy");
+        assert_eq!(base64_encode(b"\x00"), "AA==");
+        assert_eq!(base64_encode(b"\xfb\xff\xbf"), "+/+/");
+        assert_eq!(base64_encode(b"user:"), "dXNlcjo=");
+        assert_eq!(base64_encode(b":"), "Og==");
+    }
+
+    #[test]
+    fn parse_connect() {
+        let head = RequestHead::parse(
+            b"CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\nProxy-Connection:keep-alive",
+        )
+        .unwrap();
+        assert_eq!(head.method, "CONNECT");
+        assert_eq!(head.target, "example.com:443");
+        assert_eq!(head.version, "HTTP/1.1");
+        assert_eq!(head.header("host"), Some("example.com:443"));
+        assert_eq!(head.header("PROXY-CONNECTION"), Some("keep-alive"));
+        assert_eq!(head.header("connection"), None);
+        assert_eq!(
+            parse_authority(&head.target, 443),
+            Some(hostname("example.com", 443))
+        );
+        assert_eq!(
+            parse_authority("example.com", 443),
+            Some(hostname("example.com", 443))
+        );
+        assert_eq!(
+            parse_authority("192.0.2.1:8443", 443),
+            Some(ip(Ipv4Addr::new(192, 0, 2, 1).into(), 8443))
+        );
+
+        let established = status_line(&Socks5Reply::Success {
+            bnd: ip(Ipv4Addr::UNSPECIFIED.into(), 0),
+        });
+        assert_eq!(
+            response(&head.version, established),
+            "HTTP/1.1 200 Connection established\r\n\r\n"
+        );
+        assert_eq!(
+            response(
+                &head.version,
+                status_line(&Socks5Reply::Error(Socks5Error::HostUnreachable))
+            ),
+            "HTTP/1.1 502 Bad Gateway\r\nContent-Length: 0\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn parse_without_headers() {
+        let head = RequestHead::parse(b"GET http://example.com/ HTTP/1.0").unwrap();
+        assert_eq!(head.method, "GET");
+        assert!(head.headers.is_empty());
+        assert_eq!(
+            head.marshal(),
+            b"GET http://example.com/ HTTP/1.0\r\n\r\n".to_vec()
+        );
+    }
+
+    #[test]
+    fn parse_malformed() {
+        assert!(RequestHead::parse(b"").is_none());
+        assert!(RequestHead::parse(b"GET /").is_none());
+        assert!(RequestHead::parse(b"GET / HTTP/1.1 extra").is_none());
+        assert!(RequestHead::parse(b"GET  / HTTP/1.1").is_none());
+        assert!(RequestHead::parse(b"GET / HTTP/1.1\r\nno colon").is_none());
+        assert!(RequestHead::parse(b"GET /\xff HTTP/1.1").is_none());
+    }
+
+    #[test]
+    fn absolute_uri() {
+        assert_eq!(
+            split_absolute_uri("http://example.com:8080/index.html?q=1"),
+            Some((hostname("example.com", 8080), "/index.html?q=1".into()))
+        );
+        assert_eq!(
+            split_absolute_uri("HTTP://example.com/"),
+            Some((hostname("example.com", 80), "/".into()))
+        );
+        assert_eq!(
+            split_absolute_uri("http://example.com"),
+            Some((hostname("example.com", 80), "/".into()))
+        );
+        assert_eq!(
+            split_absolute_uri("http://192.0.2.1/a/b"),
+            Some((ip(Ipv4Addr::new(192, 0, 2, 1).into(), 80), "/a/b".into()))
+        );
+    }
+
+    #[test]
+    fn absolute_uri_ipv6() {
+        assert_eq!(
+            split_absolute_uri("http://[::1]:8080/"),
+            Some((ip(Ipv6Addr::LOCALHOST.into(), 8080), "/".into()))
+        );
+        assert_eq!(
+            split_absolute_uri("http://[2001:db8::1]/path"),
+            Some((ip("2001:db8::1".parse().unwrap(), 80), "/path".into()))
+        );
+        assert_eq!(split_absolute_uri("http://::1/"), None);
+        assert_eq!(
+            parse_authority("[::1]", 443),
+            Some(ip(Ipv6Addr::LOCALHOST.into(), 443))
+        );
+    }
+
+    #[test]
+    fn absolute_uri_malformed() {
+        assert_eq!(split_absolute_uri("/index.html"), None);
+        assert_eq!(split_absolute_uri("https://example.com/"), None);
+        assert_eq!(split_absolute_uri("http://"), None);
+        assert_eq!(split_absolute_uri("http:/"), None);
+        assert_eq!(split_absolute_uri("http://example.com:http/"), None);
+        assert_eq!(split_absolute_uri("http://example.com:65536/"), None);
+    }
+}
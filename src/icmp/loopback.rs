@@ -0,0 +1,80 @@
+/*
+Copyright 2021 Chengyuan Ma
+
+Permission is hereby granted, free of charge, to any person obtaining a copy of this software and
+associated documentation files (the "Software"), to deal in the Software without restriction,
+including without limitation the rights to use, copy, modify, merge, publish, distribute, sub-
+-license, and/or sell copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all copies or substantial
+portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING BUT
+NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NON-
+-INFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES
+OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+*/
+
+//! An in-memory transport linking two instances in the same process.
+
+use super::{Endpoint, PacketSender, Transport};
+use std::future::pending;
+use std::net::Ipv4Addr;
+use tokio::sync::mpsc::{channel, Receiver};
+use tokio::sync::Mutex;
+
+/// One end of a loopback link, made by [Transport::pair].
+pub struct Loopback {
+    /// The IP this end is reached at.
+    ip: Ipv4Addr,
+    /// The IP of the other end.
+    peer_ip: Ipv4Addr,
+    tx: PacketSender,
+    rx: Mutex<Receiver<(Endpoint, Vec<u8>)>>,
+}
+
+impl Loopback {
+    pub(super) fn pair(a: Ipv4Addr, b: Ipv4Addr, buffer: usize) -> (Transport, Transport) {
+        let (a_tx, b_rx) = channel(buffer);
+        let (b_tx, a_rx) = channel(buffer);
+        let a_end = Loopback {
+            ip: a,
+            peer_ip: b,
+            tx: a_tx,
+            rx: Mutex::new(a_rx),
+        };
+        let b_end = Loopback {
+            ip: b,
+            peer_ip: a,
+            tx: b_tx,
+            rx: Mutex::new(b_rx),
+        };
+        (Transport::Loopback(a_end), Transport::Loopback(b_end))
+    }
+
+    pub(super) fn sender(&self) -> PacketSender {
+        self.tx.clone()
+    }
+
+    /// Packets the other end addressed to another IP are lost, as they would be on the wire. The
+    /// echo identifier is kept, like a client's identifier is on both requests and replies.
+    pub(super) async fn receive_packet(&self) -> (Endpoint, Vec<u8>) {
+        let mut rx = self.rx.lock().await;
+        loop {
+            match rx.recv().await {
+                Some((to, packet)) if to.ip == self.ip => {
+                    let from = Endpoint {
+                        ip: self.peer_ip,
+                        id: to.id,
+                    };
+                    return (from, packet);
+                }
+                Some(_) => {}
+                // The other end is gone, so nothing will ever arrive
+                None => return pending().await,
+            }
+        }
+    }
+}
@@ -0,0 +1,74 @@
+/*
+Copyright 2021 Chengyuan Ma
+
+Permission is hereby granted, free of charge, to any person obtaining a copy of this software and
+associated documentation files (the "Software"), to deal in the Software without restriction,
+including without limitation the rights to use, copy, modify, merge, publish, distribute, sub-
+-license, and/or sell copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all copies or substantial
+portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING BUT
+NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NON-
+-INFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES
+OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+*/
+
+//! Ekho tunnels TCP and UDP traffic (or raw IP packets, in TUN mode) through ICMP echo messages.
+//!
+//! Reliability comes from [KCP](kcp::ControlBlock), which runs over the [ICMP
+//! transport](icmp::Transport). Each [Session] is one KCP conversation, encrypted with
+//! ChaCha20-Poly1305. Proxy requests travel over sessions as SOCKS5 messages (see [socks5]).
+//!
+//! Everything a peer needs lives in an [Ekho] instance, created from a [Config]:
+//!
+//! ```no_run
+//! # async fn run() -> anyhow::Result<()> {
+//! let config = ekho::config::load_config_from_file("config.toml").await?;
+//! let ekho = ekho::Ekho::bind(config).await?;
+//! if ekho.config().remote.is_some() {
+//!     ekho::client::run(ekho).await?;
+//! } else {
+//!     ekho::server::run(ekho).await;
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Sessions can also be used directly, e.g. `ekho.connect(peer)` on one side and
+//! `ekho.accept().await` on the other.
+//!
+//! # Features
+//!
+//! - `icmp` (default): the ICMP transport on raw sockets. Without it, [Ekho::bind] fails, but the
+//!   KCP implementation and the protocol types remain usable.
+
+pub mod admin;
+pub mod capture;
+pub mod client;
+pub mod config;
+pub mod ekho;
+pub mod forward;
+mod http;
+pub mod icmp;
+pub mod listen;
+pub mod log;
+pub mod metrics;
+pub mod relay;
+pub mod route;
+pub mod server;
+pub mod session;
+pub mod socks5;
+pub mod transparent;
+pub mod tun;
+pub mod udp;
+
+pub use ekho_kcp as kcp;
+
+pub use crate::config::Config;
+pub use crate::ekho::Ekho;
+pub use crate::kcp::ControlBlock;
+pub use crate::session::Session;
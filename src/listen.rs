@@ -0,0 +1,146 @@
+/*
+Copyright 2021 Chengyuan Ma
+
+Permission is hereby granted, free of charge, to any person obtaining a copy of this software and
+associated documentation files (the "Software"), to deal in the Software without restriction,
+including without limitation the rights to use, copy, modify, merge, publish, distribute, sub-
+-license, and/or sell copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all copies or substantial
+portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING BUT
+NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NON-
+-INFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES
+OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+*/
+
+//! Inbound listeners of the client.
+//!
+//! ```toml
+//! [[listen]]
+//! address = "[::]:1080"
+//! protocol = "socks5"
+//! auth = { username = "alice", password = "secret" }
+//!
+//! [[listen]]
+//! address = "127.0.0.1:2222"
+//! protocol = "forward"
+//! target = "10.0.0.2:22"
+//!
+//! [[listen]]
+//! address = "0.0.0.0:12345"
+//! protocol = "redirect"
+//! ```
+//!
+//! See [crate::transparent] for setting up `redirect` and `tproxy` listeners.
+
+use crate::socks5::Socks5SocketAddr;
+use crate::transparent::set_transparent;
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Protocol as SocketProtocol, Socket, Type};
+use std::fmt;
+use std::net::SocketAddr;
+use tokio::net::TcpListener;
+
+/// The protocol spoken by clients of a listener.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    /// SOCKS5 and HTTP proxy on the same port, told apart by the first byte.
+    Mixed,
+    Socks5,
+    Http,
+    /// Every connection is forwarded to a fixed `target` through the server.
+    Forward,
+    /// Transparent proxy for connections diverted by netfilter `REDIRECT`.
+    Redirect,
+    /// Transparent proxy for connections diverted by netfilter `TPROXY`.
+    Tproxy,
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Protocol::Mixed => "mixed",
+            Protocol::Socks5 => "socks5",
+            Protocol::Http => "http",
+            Protocol::Forward => "forward",
+            Protocol::Redirect => "redirect",
+            Protocol::Tproxy => "tproxy",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Credentials required from proxy clients (SOCKS5 username/password or HTTP basic auth).
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Auth {
+    pub username: String,
+    #[serde(skip_serializing)]
+    pub password: String,
+}
+
+/// Configuration of a single listener.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Config {
+    pub address: SocketAddr,
+    #[serde(default = "default_protocol")]
+    pub protocol: Protocol,
+    #[serde(default)]
+    pub auth: Option<Auth>,
+    /// The destination of a `forward` listener.
+    #[serde(default)]
+    pub target: Option<Socks5SocketAddr>,
+    /// Whether an IPv6 listener also accepts IPv4 connections.
+    #[serde(default = "default_dual_stack")]
+    pub dual_stack: bool,
+}
+
+fn default_protocol() -> Protocol {
+    Protocol::Mixed
+}
+
+fn default_dual_stack() -> bool {
+    true
+}
+
+/// The listener used when none is configured.
+pub fn default_listen() -> Vec<Config> {
+    vec![Config {
+        address: SocketAddr::from(([127, 0, 0, 1], 23336)),
+        protocol: Protocol::Mixed,
+        auth: None,
+        target: None,
+        dual_stack: true,
+    }]
+}
+
+impl Config {
+    /// Binds the listening socket.
+    pub fn bind(&self) -> Result<TcpListener> {
+        if self.protocol == Protocol::Forward && self.target.is_none() {
+            bail!("forward listener on {} has no target", self.address);
+        }
+        let socket = Socket::new(
+            Domain::for_address(self.address),
+            Type::STREAM,
+            Some(SocketProtocol::TCP),
+        )?;
+        if self.address.is_ipv6() {
+            socket.set_only_v6(!self.dual_stack)?;
+        }
+        #[cfg(unix)]
+        socket.set_reuse_address(true)?;
+        if self.protocol == Protocol::Tproxy {
+            set_transparent(&socket, self.address.is_ipv6())?;
+        }
+        socket.bind(&self.address.into())?;
+        socket.listen(1024)?;
+        socket.set_nonblocking(true)?;
+        Ok(TcpListener::from_std(socket.into())?)
+    }
+}
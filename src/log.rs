@@ -0,0 +1,258 @@
+/*
+Copyright 2021 Chengyuan Ma
+
+Permission is hereby granted, free of charge, to any person obtaining a copy of this software and
+associated documentation files (the "Software"), to deal in the Software without restriction,
+including without limitation the rights to use, copy, modify, merge, publish, distribute, sub-
+-license, and/or sell copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all copies or substantial
+portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING BUT
+NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NON-
+-INFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES
+OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+*/
+//! Logging.
+//!
+//! ```toml
+//! [log]
+//! level = "info"
+//! # Levels of particular modules, overriding `level`
+//! filter = "ekho::session=debug,ekho_kcp=warn"
+//! # compact (the default), full or json, which writes an object per line for log shippers
+//! format = "json"
+//! # Logs to /var/log/ekho/ekho.log.<date> instead of stdout, starting a new file every day
+//! file = "/var/log/ekho/ekho.log"
+//! rotation = "daily"
+//! # Records the time spent in spans, for flame graphs
+//! flame = "ekho.folded"
+//! ```
+//!
+//! The filter takes comma-separated `target=level` directives as in `RUST_LOG`. Targets are module
+//! paths; note that those of the KCP implementation start with `ekho_kcp`, not `ekho::kcp`.
+//!
+//! The flame output is in the folded stack format, which `inferno-flamegraph` or `flamegraph.pl`
+//! turn into a flame graph. Only spans passing the filter are recorded, so it is best combined
+//! with `level = "trace"` and a filter turning down whatever is not of interest.
+//!
+//! `level` and `filter` can be reloaded; the rest needs a restart.
+
+use crate::kcp::ConfigError;
+use anyhow::{Context, Result};
+use derivative::Derivative;
+use lazy_static::lazy_static;
+use parking_lot::Mutex;
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fs::{self, File};
+use std::io::{self, BufWriter};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use thiserror::Error;
+use tracing::Level;
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_appender::rolling::{self, RollingFileAppender};
+use tracing_flame::{FlameLayer, FlushGuard};
+use tracing_subscriber::filter::{EnvFilter, ParseError};
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::layer::{Layer, Layered};
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::{fmt, reload, Registry};
+
+/// Logging configuration.
+#[derive(Clone, Debug, Deserialize, Serialize, Derivative)]
+#[derivative(Default)]
+#[serde(default)]
+pub struct Config {
+    /// The most verbose level logged: one of `error`, `warn`, `info`, `debug` and `trace`.
+    #[derivative(Default(value = "Level::INFO"))]
+    #[serde(
+        deserialize_with = "deserialize_level",
+        serialize_with = "serialize_level"
+    )]
+    pub level: Level,
+    /// Levels of particular targets, overriding `level`, e.g. `ekho::session=debug,ekho_kcp=warn`.
+    pub filter: String,
+    #[derivative(Default(value = "Format::Compact"))]
+    pub format: Format,
+    /// Logs to this file instead of stdout.
+    pub file: Option<PathBuf>,
+    /// How often a new log file is started. Rotated files are named after the time they start.
+    #[derivative(Default(value = "Rotation::Daily"))]
+    pub rotation: Rotation,
+    /// Records the time spent in spans to this file, in the folded stack format.
+    pub flame: Option<PathBuf>,
+}
+
+/// The format of log lines.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    /// One short line per event.
+    Compact,
+    /// One line per event, with the fields of all enclosing spans.
+    Full,
+    /// One JSON object per event.
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = ParseFormatError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "compact" => Ok(Format::Compact),
+            "full" => Ok(Format::Full),
+            "json" => Ok(Format::Json),
+            _ => Err(ParseFormatError(s.into())),
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+#[error("invalid log format {0:?}, expected compact, full or json")]
+pub struct ParseFormatError(String);
+
+/// How often log files are rotated.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Rotation {
+    Minutely,
+    Hourly,
+    Daily,
+    Never,
+}
+
+impl From<Rotation> for rolling::Rotation {
+    fn from(rotation: Rotation) -> Self {
+        match rotation {
+            Rotation::Minutely => rolling::Rotation::MINUTELY,
+            Rotation::Hourly => rolling::Rotation::HOURLY,
+            Rotation::Daily => rolling::Rotation::DAILY,
+            Rotation::Never => rolling::Rotation::NEVER,
+        }
+    }
+}
+
+fn deserialize_level<'de, D: Deserializer<'de>>(d: D) -> Result<Level, D::Error> {
+    String::deserialize(d)?.parse().map_err(D::Error::custom)
+}
+
+fn serialize_level<S: Serializer>(level: &Level, s: S) -> Result<S::Ok, S::Error> {
+    s.collect_str(&level.to_string().to_lowercase())
+}
+
+impl Config {
+    /// Checks the filter and the log file, and returns every problem found.
+    pub fn validate(&self) -> Vec<ConfigError> {
+        let mut errors = Vec::new();
+        if let Err(err) = env_filter(self.level, &self.filter) {
+            errors.push(ConfigError::new("filter", err.to_string()));
+        }
+        if matches!(&self.file, Some(file) if file.file_name().is_none()) {
+            errors.push(ConfigError::new("file", "must be the path of a file"));
+        }
+        errors
+    }
+}
+
+/// Combines `level` with the directives in `filter`.
+fn env_filter(level: Level, filter: &str) -> Result<EnvFilter, ParseError> {
+    let directives: Vec<_> = std::iter::once(level.to_string())
+        .chain(
+            filter
+                .split(',')
+                .map(str::trim)
+                .filter(|directive| !directive.is_empty())
+                .map(String::from),
+        )
+        .collect();
+    EnvFilter::try_new(directives.join(","))
+}
+
+type Filtered = Layered<reload::Layer<EnvFilter, Registry>, Registry>;
+
+lazy_static! {
+    /// Changes the filter of the subscriber installed by [init], and remembers the directives
+    /// in effect besides the level.
+    static ref FILTER: Mutex<Option<(reload::Handle<EnvFilter, Registry>, String)>> =
+        Mutex::new(None);
+}
+
+/// Flushes the log file and the flame output when dropped. Keep it for as long as logging.
+pub struct Guard {
+    _file: Option<WorkerGuard>,
+    _flame: Option<FlushGuard<BufWriter<File>>>,
+}
+
+/// Installs the global subscriber. Can only be called once per process.
+pub fn init(config: &Config) -> Result<Guard> {
+    let (filter, handle) = reload::Layer::new(env_filter(config.level, &config.filter)?);
+    let (writer, file_guard) = match &config.file {
+        Some(path) => {
+            let directory = match path.parent() {
+                Some(directory) if directory != Path::new("") => directory,
+                _ => Path::new("."),
+            };
+            fs::create_dir_all(directory)
+                .with_context(|| format!("creating {}", directory.display()))?;
+            let appender = RollingFileAppender::new(
+                config.rotation.into(),
+                directory,
+                path.file_name().context("no log file name")?,
+            );
+            let (writer, guard) = tracing_appender::non_blocking(appender);
+            (BoxMakeWriter::new(writer), Some(guard))
+        }
+        None => (BoxMakeWriter::new(io::stdout), None),
+    };
+    // No colors in files
+    let ansi = config.file.is_none();
+    let fmt: Box<dyn Layer<Filtered> + Send + Sync> = match config.format {
+        Format::Compact => Box::new(fmt::layer().compact().with_ansi(ansi).with_writer(writer)),
+        Format::Full => Box::new(fmt::layer().with_ansi(ansi).with_writer(writer)),
+        Format::Json => Box::new(fmt::layer().json().with_writer(writer)),
+    };
+    let (flame, flame_guard) = match &config.flame {
+        Some(path) => {
+            let (layer, guard) = FlameLayer::with_file(path)
+                .with_context(|| format!("creating {}", path.display()))?;
+            (Some(layer), Some(guard))
+        }
+        None => (None, None),
+    };
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(fmt)
+        .with(flame)
+        .init();
+    *FILTER.lock() = Some((handle, config.filter.clone()));
+    Ok(Guard {
+        _file: file_guard,
+        _flame: flame_guard,
+    })
+}
+
+/// Puts the level and filter of `config` into effect in the subscriber installed by [init], if
+/// any. The filter must be valid.
+pub fn reload(config: &Config) {
+    if let Some((handle, filter)) = &mut *FILTER.lock() {
+        if let Ok(new) = env_filter(config.level, &config.filter) {
+            let _ = handle.reload(new);
+            *filter = config.filter.clone();
+        }
+    }
+}
+
+/// Changes the level of the subscriber installed by [init], if any, keeping its filter.
+pub fn set_level(level: Level) {
+    if let Some((handle, filter)) = &*FILTER.lock() {
+        if let Ok(new) = env_filter(level, filter) {
+            let _ = handle.reload(new);
+        }
+    }
+}
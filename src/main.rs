@@ -17,70 +17,400 @@ OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
 CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
 */
 
-mod client;
-mod config;
-mod icmp;
-mod kcp;
-mod relay;
-mod server;
-mod session;
-mod socks5;
+use anyhow::{bail, Context, Result};
+use ekho::admin::{self, Request};
+use ekho::config::template;
+use ekho::icmp::Endpoint;
+use ekho::listen::{self, Protocol};
+use ekho::log::Format;
+use ekho::{client, config, log, metrics, server, Config, Ekho};
+use rand::random;
+use serde_json::Value;
+use std::future::Future;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use structopt::StructOpt;
+use tokio::fs::OpenOptions;
+use tokio::io::AsyncWriteExt;
+#[cfg(unix)]
+use tokio::signal::unix::SignalKind;
+use tokio::{select, signal};
+use tracing::{error, info, Level};
 
-use crate::config::config;
-use anyhow::Result;
-use std::env;
+/// Proxy and tunnel over ICMP echo.
+#[derive(StructOpt)]
+#[structopt(name = "ekho")]
+enum Command {
+    /// Runs a client, which connects to the server at `remote`
+    Client(RunOptions),
+    /// Runs a server, which accepts sessions from clients
+    Server(RunOptions),
+    /// Prints a random key for the `key` setting
+    Genkey,
+    /// Writes commented config files for a server and/or a client sharing a key
+    Init {
+        /// Writes a server config, to config.toml unless a file is given
+        #[structopt(long)]
+        server: Option<Option<PathBuf>>,
+        /// Writes a client config, to config.toml unless a file is given
+        #[structopt(long)]
+        client: Option<Option<PathBuf>>,
+        /// The server the client connects to, as IP:ID (the IP defaults to a placeholder and the
+        /// ID to a random one)
+        #[structopt(short, long)]
+        remote: Option<Endpoint>,
+        /// Uses this key (e.g. an existing server's) instead of a new one
+        #[structopt(short, long)]
+        key: Option<String>,
+    },
+    /// Loads a config file and reports every problem in it
+    CheckConfig(ConfigOptions),
+    /// Inspects and steers a running instance through its admin socket
+    Ctl {
+        #[structopt(flatten)]
+        config: ConfigOptions,
+        /// The admin socket, instead of `admin.socket` in the config file
+        #[structopt(short, long, parse(from_os_str))]
+        socket: Option<PathBuf>,
+        #[structopt(subcommand)]
+        command: CtlCommand,
+    },
+    /// Sends a file through a pair of KCP control blocks over a simulated lossy link
+    Bench {
+        #[structopt(flatten)]
+        config: ConfigOptions,
+        /// The file to send
+        #[structopt(default_value = "sample", parse(from_os_str))]
+        file: PathBuf,
+    },
+}
 
-use crate::relay::relay_kcp;
-use tracing::info;
-use tracing::Level;
-use tracing_subscriber::util::SubscriberInitExt;
+#[derive(StructOpt)]
+enum CtlCommand {
+    /// Lists the live sessions
+    Sessions {
+        /// Prints the full statistics as JSON instead of a table
+        #[structopt(long)]
+        json: bool,
+    },
+    /// Resets a session
+    Kill {
+        /// The peer, as IP:ID
+        peer: Endpoint,
+        conv: u32,
+    },
+    /// Shows the configuration in effect, without the key and passwords
+    Config,
+    /// Changes the log level until the configuration is reloaded
+    LogLevel { level: Level },
+    /// Reloads the configuration, like SIGHUP
+    Reload,
+}
 
-fn setep_subscriber() {
-    // let (flame_layer, _guard) = tracing_flame::FlameLayer::with_file("./tracing.folded")?;
-    // let (tracer, _uninstall) = opentelemetry_jaeger::new_pipeline()
-    //     .with_service_name("ekho")
-    //     .install()?;
-    let fmt_subscriber = tracing_subscriber::fmt::fmt()
-        .with_max_level(Level::DEBUG)
-        .compact()
-        .finish();
-    fmt_subscriber.init();
+#[derive(StructOpt, Clone)]
+struct ConfigOptions {
+    /// The config file
+    #[structopt(short, long, default_value = "config.toml", parse(from_os_str))]
+    config: PathBuf,
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    let _guard = setep_subscriber();
-    let config_path = env::args()
-        .nth(1)
-        .unwrap_or_else(|| String::from("config.toml"));
+impl ConfigOptions {
+    async fn load(&self) -> Result<Config> {
+        config::load_config_from_file(&self.config)
+            .await
+            .with_context(|| format!("in {}", self.config.display()))
+    }
+}
 
-    info!("Ekho (experimental asynchronous implementation) by Chengyuan Ma");
+#[derive(StructOpt, Clone)]
+struct RunOptions {
+    #[structopt(flatten)]
+    config: ConfigOptions,
+    /// Overrides `remote`: the server to connect to, as IP:ID
+    #[structopt(short, long)]
+    remote: Option<Endpoint>,
+    /// Overrides `listen` with mixed SOCKS5/HTTP listeners on the given addresses (repeatable)
+    #[structopt(short, long, number_of_values = 1)]
+    listen: Vec<SocketAddr>,
+    /// Overrides `log.level`: one of error, warn, info, debug and trace
+    #[structopt(long)]
+    log_level: Option<Level>,
+    /// Overrides `log.filter`: levels of particular modules, e.g. ekho::session=debug
+    #[structopt(long)]
+    log_filter: Option<String>,
+    /// Overrides `log.format`: one of compact, full and json
+    #[structopt(long)]
+    log_format: Option<Format>,
+    /// Overrides `log.flame`: records the time spent in spans to this file, for flame graphs
+    #[structopt(long, parse(from_os_str))]
+    flame: Option<PathBuf>,
+}
 
-    config::load_config_from_file(config_path).await?;
-    kcp_test::test().await;
-    return Ok(());
-    icmp::init_send_recv_loop().await?;
-    session::init_dispatch_loop().await;
+impl RunOptions {
+    /// Loads the config file and applies the overrides given on the command line.
+    async fn load(&self) -> Result<Config> {
+        let mut config = self.config.load().await?;
+        if let Some(remote) = self.remote {
+            config.remote = Some(remote);
+        }
+        if !self.listen.is_empty() {
+            config.listen = self
+                .listen
+                .iter()
+                .map(|&address| listen::Config {
+                    address,
+                    protocol: Protocol::Mixed,
+                    auth: None,
+                    target: None,
+                    dual_stack: true,
+                })
+                .collect();
+        }
+        if let Some(level) = self.log_level {
+            config.log.level = level;
+        }
+        if let Some(filter) = &self.log_filter {
+            config.log.filter = filter.clone();
+        }
+        if let Some(format) = self.log_format {
+            config.log.format = format;
+        }
+        if let Some(flame) = &self.flame {
+            config.log.flame = Some(flame.clone());
+        }
+        Ok(config)
+    }
+}
 
-    if config().remote.is_some() {
-        client::run().await;
+/// Writes `content` to a file that must not exist yet.
+async fn write_new(path: &Path, content: &str) -> Result<()> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)
+        .await
+        .with_context(|| format!("creating {}", path.display()))?;
+    file.write_all(content.as_bytes()).await?;
+    Ok(())
+}
+
+/// Prints the sessions listed by the admin socket as a table.
+fn print_sessions(sessions: &Value) {
+    println!(
+        "{:<24} {:>10} {:>8} {:>12} {:>12} {:>6} {:>6} {:>6} {:>8}  STATE",
+        "PEER", "CONV", "AGE", "SENT", "RECEIVED", "SRTT", "RTO", "RETX", "INFLIGHT"
+    );
+    for session in sessions.as_array().into_iter().flatten() {
+        let stats = &session["stats"];
+        println!(
+            "{:<24} {:>10} {:>7.0}s {:>12} {:>12} {:>6} {:>6} {:>5.1}% {:>8}  {}",
+            format!(
+                "{}:{}",
+                session["peer"]["ip"].as_str().unwrap_or("?"),
+                session["peer"]["id"]
+            ),
+            session["conv"].as_u64().unwrap_or_default(),
+            session["age"].as_f64().unwrap_or_default(),
+            stats["bytes_acked"].as_u64().unwrap_or_default(),
+            stats["bytes_received"].as_u64().unwrap_or_default(),
+            stats["srtt"].as_u64().unwrap_or_default(),
+            stats["rto"].as_u64().unwrap_or_default(),
+            stats["retransmit_ratio"].as_f64().unwrap_or_default() * 100.0,
+            stats["inflight"].as_u64().unwrap_or_default(),
+            stats["state"].as_str().unwrap_or("?"),
+        );
+    }
+}
+
+/// Prints the report of a reload requested through the admin socket.
+fn print_reload(reload: &Value) {
+    let restart_required: Vec<_> = reload["restart_required"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(Value::as_str)
+        .collect();
+    if restart_required.is_empty() {
+        println!("configuration reloaded");
     } else {
-        server::run().await;
+        println!(
+            "configuration reloaded; changes to {} take effect after a restart",
+            restart_required.join(", ")
+        );
+    }
+}
+
+/// Reloads the configuration whenever SIGHUP is received.
+#[cfg(unix)]
+async fn reload_on_hangup(ekho: &Ekho, options: &RunOptions) -> Result<()> {
+    let mut hangup = signal::unix::signal(SignalKind::hangup())?;
+    while hangup.recv().await.is_some() {
+        let config = options.load().await;
+        match config.and_then(|config| Ok(ekho.reload(config)?)) {
+            Ok(reload) => info!("{}", reload),
+            Err(err) => error!("not reloading: {:#}", err),
+        }
     }
     Ok(())
 }
 
+#[cfg(not(unix))]
+async fn reload_on_hangup(_ekho: &Ekho, _options: &RunOptions) -> Result<()> {
+    std::future::pending().await
+}
+
+/// Runs `run` on a new Ekho instance until it returns or Ctrl-C is hit, reloading the
+/// configuration on SIGHUP.
+async fn start<F, Fut>(options: &RunOptions, config: Config, run: F) -> Result<()>
+where
+    F: FnOnce(Ekho) -> Fut,
+    Fut: Future<Output = Result<()>>,
+{
+    let _guard = log::init(&config.log)?;
+    info!("Ekho (experimental asynchronous implementation) by Chengyuan Ma");
+    let ekho = Ekho::bind(config).await?;
+    metrics::start(&ekho).await?;
+    let loader_options = options.clone();
+    let loader: admin::Loader = Arc::new(move || {
+        let options = loader_options.clone();
+        Box::pin(async move { options.load().await })
+    });
+    admin::start(&ekho, loader).await?;
+    let res = select! {
+        res = run(ekho.clone()) => res,
+        res = reload_on_hangup(&ekho, options) => res,
+        _ = signal::ctrl_c() => {
+            info!("shutting down");
+            Ok(())
+        }
+    };
+    ekho.shutdown().await;
+    res
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    match Command::from_args() {
+        Command::Client(options) => {
+            let config = options.load().await?;
+            if config.remote.is_none() {
+                bail!("no server to connect to: set `remote` or pass --remote");
+            }
+            start(&options, config, client::run).await
+        }
+        Command::Server(options) => {
+            let config = options.load().await?;
+            if config.remote.is_some() {
+                bail!("`remote` is set, but only clients connect to a server");
+            }
+            start(&options, config, |ekho| async {
+                server::run(ekho).await;
+                Ok(())
+            })
+            .await
+        }
+        Command::Genkey => {
+            println!("{}", hex::encode(config::generate_key()));
+            Ok(())
+        }
+        Command::Init {
+            server,
+            client,
+            remote,
+            key,
+        } => {
+            let default = || PathBuf::from("config.toml");
+            let server = server.map(|path| path.unwrap_or_else(default));
+            let client = client.map(|path| path.unwrap_or_else(default));
+            if server.is_none() && client.is_none() {
+                bail!("nothing to write: pass --server, --client or both");
+            }
+            if server.is_some() && server == client {
+                bail!("--server and --client need different files");
+            }
+            let key = match key {
+                Some(key) => config::parse_key(&key)?,
+                None => config::generate_key(),
+            };
+            if let Some(path) = server {
+                write_new(&path, &template::server(&key)).await?;
+                println!("wrote server config to {}", path.display());
+            }
+            if let Some(path) = client {
+                let placeholder = Endpoint {
+                    ip: Ipv4Addr::new(203, 0, 113, 1),
+                    id: random(),
+                };
+                write_new(
+                    &path,
+                    &template::client(&key, remote.unwrap_or(placeholder)),
+                )
+                .await?;
+                println!("wrote client config to {}", path.display());
+                if remote.is_none() {
+                    println!("set `remote` in it to the address of the server");
+                }
+            }
+            Ok(())
+        }
+        Command::CheckConfig(options) => {
+            options.load().await?;
+            println!("{}: OK", options.config.display());
+            Ok(())
+        }
+        Command::Ctl {
+            config,
+            socket,
+            command,
+        } => {
+            let socket = match socket {
+                Some(socket) => socket,
+                None => config.load().await?.admin.socket.context(
+                    "no admin socket: set `admin.socket` in the config file or pass --socket",
+                )?,
+            };
+            let request = match &command {
+                CtlCommand::Sessions { .. } => Request::Sessions,
+                CtlCommand::Kill { peer, conv } => Request::Kill {
+                    peer: *peer,
+                    conv: *conv,
+                },
+                CtlCommand::Config => Request::Config,
+                CtlCommand::LogLevel { level } => Request::LogLevel {
+                    level: level.to_string(),
+                },
+                CtlCommand::Reload => Request::Reload,
+            };
+            let result = admin::request(&socket, &request).await?;
+            match command {
+                CtlCommand::Sessions { json: false } => print_sessions(&result),
+                CtlCommand::Sessions { json: true } | CtlCommand::Config => {
+                    println!("{}", serde_json::to_string_pretty(&result)?)
+                }
+                CtlCommand::Reload => print_reload(&result),
+                CtlCommand::Kill { .. } | CtlCommand::LogLevel { .. } => {}
+            }
+            Ok(())
+        }
+        Command::Bench { config, file } => {
+            let config = config.load().await?;
+            let _guard = log::init(&config.log)?;
+            kcp_test::test(config.kcp, file).await
+        }
+    }
+}
+
 #[allow(dead_code)]
 mod file_test {
-    use crate::config::config;
-    use crate::session;
     use anyhow::Result;
+    use ekho::{session, Ekho};
     use tokio::io::{AsyncReadExt, AsyncWriteExt};
     use tracing::info;
 
-    pub async fn test() -> Result<()> {
-        if config().remote.is_some() {
-            let session = session::Session::new(config().remote.unwrap(), 998244353);
+    pub async fn test(ekho: Ekho) -> Result<()> {
+        if let Some(remote) = ekho.config().remote {
+            let session = session::Session::new(ekho.clone(), remote, 998244353);
             session.send(b"\0").await;
             let mut file = tokio::fs::File::create("sample").await?;
             loop {
@@ -93,11 +423,11 @@ mod file_test {
             session.close().await;
             info!("closed");
         } else {
-            let session = session::Session::incoming().await;
+            let session = ekho.accept().await;
             let _greeting = session.recv().await;
             info!("received session: {:?}", session);
             let mut file = tokio::fs::File::open("sample").await?;
-            let mut buf = vec![0u8; config().kcp.mss()];
+            let mut buf = vec![0u8; ekho.config().kcp.mss()];
             loop {
                 let len = file.read(&mut buf).await?;
                 if len == 0 {
@@ -112,18 +442,18 @@ mod file_test {
     }
 }
 
-#[allow(dead_code)]
 mod kcp_test {
-    use crate::config::config;
-    use crate::kcp::{ControlBlock, Error};
+    use anyhow::Result;
     use derivative::Derivative;
+    use ekho::kcp::{Config, ControlBlock, Error};
     use lazy_static::lazy_static;
-    use parking_lot::Mutex;
+    use parking_lot::{Mutex, MutexGuard};
     use rand::distributions::Bernoulli;
     use rand::thread_rng;
     use rand_distr::{Binomial, Distribution};
     use std::cmp::Reverse;
     use std::collections::BinaryHeap;
+    use std::path::Path;
     use std::sync::Arc;
     use tokio::io::AsyncReadExt;
     use tokio::sync::Notify;
@@ -178,24 +508,32 @@ mod kcp_test {
     lazy_static! {
         static ref A_B: Mutex<Network> = Mutex::new(Network::new(100, 5.0, 0.01));
         static ref B_A: Mutex<Network> = Mutex::new(Network::new(100, 5.0, 0.01));
-        static ref A: Mutex<ControlBlock> =
-            Mutex::new(ControlBlock::new(12345, config().kcp.clone()));
-        static ref B: Mutex<ControlBlock> =
-            Mutex::new(ControlBlock::new(12345, config().kcp.clone()));
         static ref A_N: Notify = Notify::new();
+        static ref EPOCH: Instant = Instant::now();
+    }
+
+    /// Locks a control block, bringing its clock up to date.
+    fn lock(kcp: &Mutex<ControlBlock>) -> MutexGuard<'_, ControlBlock> {
+        let mut kcp = kcp.lock();
+        kcp.update(EPOCH.elapsed().as_millis() as u32);
+        kcp
     }
 
-    pub async fn test() {
+    pub async fn test(config: Config, file: impl AsRef<Path>) -> Result<()> {
+        let a = Arc::new(Mutex::new(ControlBlock::new(12345, config.clone())));
+        let b = Arc::new(Mutex::new(ControlBlock::new(12345, config.clone())));
+        let (a_cloned, b_cloned) = (a.clone(), b.clone());
         let size = Arc::new(Mutex::new(0));
         let size_cloned = size.clone();
         let sent = Arc::new(Mutex::new(0));
         let sent_cloned = sent.clone();
-        let _ = task::spawn(async move {
-            let mut interval = interval(Duration::from_millis(config().kcp.interval as u64));
+        let interval_ms = config.interval as u64;
+        task::spawn(async move {
+            let mut interval = interval(Duration::from_millis(interval_ms));
             loop {
                 interval.tick().await;
                 {
-                    let mut kcp = A.lock();
+                    let mut kcp = lock(&a_cloned);
                     kcp.flush();
                     while let Some(packet) = kcp.output() {
                         *size_cloned.lock() += packet.len();
@@ -204,7 +542,7 @@ mod kcp_test {
                     A_N.notify_waiters();
                 }
                 {
-                    let mut kcp = B.lock();
+                    let mut kcp = lock(&b_cloned);
                     kcp.flush();
                     while let Some(packet) = kcp.output() {
                         B_A.lock().send(packet)
@@ -212,19 +550,22 @@ mod kcp_test {
                 }
             }
         });
-        let _ = task::spawn(async move {
+        let (a_cloned, b_cloned) = (a.clone(), b.clone());
+        task::spawn(async move {
             let mut interval = interval(Duration::from_millis(10));
             let mut first = true;
             loop {
                 interval.tick().await;
                 {
-                    let mut kcp = B.lock();
+                    let mut kcp = lock(&b_cloned);
                     while let Some(packet) = A_B.lock().recv() {
                         kcp.input(&packet).unwrap();
                     }
                     loop {
                         match kcp.recv() {
                             Err(Error::NotAvailable) => break,
+                            // The other side has finished, and so has the run
+                            Err(Error::Closed) => return,
                             Ok(packet) => {
                                 if first {
                                     first = false;
@@ -237,13 +578,13 @@ mod kcp_test {
                     }
                 }
                 {
-                    let mut kcp = A.lock();
+                    let mut kcp = lock(&a_cloned);
                     while let Some(packet) = B_A.lock().recv() {
                         kcp.input(&packet).unwrap();
                     }
                     loop {
                         match kcp.recv() {
-                            Err(Error::NotAvailable) => break,
+                            Err(Error::NotAvailable) | Err(Error::Closed) => break,
                             Ok(_) => continue,
                             Err(err) => panic!("{:?}", err),
                         }
@@ -251,11 +592,12 @@ mod kcp_test {
                 }
             }
         });
-        let mut file = tokio::fs::File::open("sample").await.unwrap();
-        let mut buf = vec![0u8; config().kcp.mss()];
+        let mut file = tokio::fs::File::open(file).await?;
+        let mut buf = vec![0u8; config.mss()];
         info!("Read file!");
-        let total = file.metadata().await.unwrap().len();
-        let _ = task::spawn(async move {
+        let total = file.metadata().await?.len();
+        let a_cloned = a.clone();
+        task::spawn(async move {
             let mut interval = interval(Duration::from_secs(1));
             loop {
                 interval.tick().await;
@@ -266,19 +608,26 @@ mod kcp_test {
                     *sent as f64 / total as f64 * 100.0,
                     *size.lock() as f64 / 1048576.0
                 );
-                A.lock().debug();
+                a_cloned.lock().debug();
             }
         });
         loop {
-            let len = file.read(&mut buf).await.unwrap();
+            let len = file.read(&mut buf).await?;
             if len == 0 {
                 info!("done!");
-                break;
+                return Ok(());
             }
-            while A.lock().wait_send() >= config().kcp.send_wnd as usize {
+            while lock(&a).wait_send() >= config.send_wnd as usize {
                 A_N.notified().await;
             }
-            A.lock().send(&buf[..len]).unwrap();
+            match lock(&a).send(&buf[..len]) {
+                Ok(()) => {}
+                Err(Error::Closed) => {
+                    info!("closed before the end of the file");
+                    return Ok(());
+                }
+                Err(err) => return Err(err.into()),
+            }
         }
     }
 }
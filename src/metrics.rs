@@ -0,0 +1,339 @@
+/*
+Copyright 2021 Chengyuan Ma
+
+Permission is hereby granted, free of charge, to any person obtaining a copy of this software and
+associated documentation files (the "Software"), to deal in the Software without restriction,
+including without limitation the rights to use, copy, modify, merge, publish, distribute, sub-
+-license, and/or sell copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all copies or substantial
+portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING BUT
+NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NON-
+-INFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES
+OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+*/
+
+//! Prometheus metrics.
+//!
+//! Every [Ekho] instance keeps its own [Metrics]. They can be exported over HTTP in the Prometheus
+//! text format by setting an address to serve them on:
+//!
+//! ```toml
+//! [metrics]
+//! listen = "127.0.0.1:9100"
+//! ```
+//!
+//! The endpoint answers `GET /metrics` and nothing else. Counters are cumulative since the
+//! instance was created; session gauges are computed on each scrape.
+
+use crate::ekho::Ekho;
+use crate::session;
+use crate::socks5::{Socks5Error, Socks5Reply};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task;
+use tracing::{debug, error, info};
+
+/// Metrics configuration.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Config {
+    /// Where to serve metrics. Metrics are not exported if unset.
+    pub listen: Option<SocketAddr>,
+}
+
+/// A monotonically increasing count.
+#[derive(Debug, Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Upper bounds (unit: ms) of the buckets of [Histogram]s.
+const BUCKETS: [u32; 10] = [10, 25, 50, 100, 250, 500, 1000, 2500, 5000, 10000];
+
+/// A distribution of durations (unit: ms).
+#[derive(Debug, Default)]
+pub struct Histogram {
+    /// Observations in each bucket; the last one is for those beyond the largest bound.
+    buckets: [Counter; BUCKETS.len() + 1],
+    sum: Counter,
+}
+
+impl Histogram {
+    pub fn observe(&self, ms: u32) {
+        let bucket = BUCKETS
+            .iter()
+            .position(|&bound| ms <= bound)
+            .unwrap_or(BUCKETS.len());
+        self.buckets[bucket].inc();
+        self.sum.add(ms as u64);
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        let mut count = 0;
+        for (bound, bucket) in BUCKETS.iter().zip(&self.buckets) {
+            count += bucket.get();
+            let _ = writeln!(
+                out,
+                "{}_bucket{{le=\"{}\"}} {}",
+                name,
+                *bound as f64 / 1000.0,
+                count
+            );
+        }
+        count += self.buckets[BUCKETS.len()].get();
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, count);
+        let _ = writeln!(out, "{}_sum {}", name, self.sum.get() as f64 / 1000.0);
+        let _ = writeln!(out, "{}_count {}", name, count);
+    }
+}
+
+/// The outcome of a SOCKS5 request, as told by the reply to it.
+#[derive(Debug, Clone, Copy)]
+enum Outcome {
+    Success,
+    Rejected,
+    Failed,
+}
+
+const OUTCOMES: [(Outcome, &str); 3] = [
+    (Outcome::Success, "success"),
+    (Outcome::Rejected, "rejected"),
+    (Outcome::Failed, "failed"),
+];
+
+/// Counters and distributions of an Ekho instance.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    /// ICMP packets (and their payload bytes) received from peers.
+    pub packets_in: Counter,
+    pub bytes_in: Counter,
+    /// ICMP packets (and their payload bytes) sent to peers.
+    pub packets_out: Counter,
+    pub bytes_out: Counter,
+    /// Packets that could not be decrypted, which are echoed back like a normal ping.
+    pub decrypt_failures: Counter,
+    /// Packets sent again after the OS ran out of buffers (ENOBUFS).
+    pub enobufs_resends: Counter,
+    /// KCP segments retransmitted.
+    pub resends: Counter,
+    /// Sessions torn down because their link was found to be dead.
+    pub dead_links: Counter,
+    /// Smoothed RTT of sessions, sampled every second.
+    pub srtt: Histogram,
+    /// Base retransmission timeout of sessions, sampled every second.
+    pub rto: Histogram,
+    socks_requests: [Counter; 3],
+}
+
+impl Metrics {
+    /// Counts a SOCKS5 request by the reply it got.
+    pub fn socks_reply(&self, reply: &Socks5Reply) {
+        let outcome = match reply {
+            Socks5Reply::Success { .. } => Outcome::Success,
+            Socks5Reply::Error(Socks5Error::ConnectionNotAllowed) => Outcome::Rejected,
+            Socks5Reply::Error(_) => Outcome::Failed,
+        };
+        self.socks_requests[outcome as usize].inc();
+    }
+}
+
+fn render_counter(out: &mut String, name: &str, help: &str, value: u64) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} counter", name);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+/// Renders the metrics of `ekho` in the Prometheus text format.
+pub async fn render(ekho: &Ekho) -> String {
+    let metrics = ekho.metrics();
+    let mut out = String::new();
+    for (name, help, counter) in [
+        (
+            "ekho_transport_packets_received_total",
+            "ICMP packets received.",
+            &metrics.packets_in,
+        ),
+        (
+            "ekho_transport_bytes_received_total",
+            "Payload bytes of ICMP packets received.",
+            &metrics.bytes_in,
+        ),
+        (
+            "ekho_transport_packets_sent_total",
+            "ICMP packets sent.",
+            &metrics.packets_out,
+        ),
+        (
+            "ekho_transport_bytes_sent_total",
+            "Payload bytes of ICMP packets sent.",
+            &metrics.bytes_out,
+        ),
+        (
+            "ekho_transport_enobufs_resends_total",
+            "ICMP packets sent again after ENOBUFS.",
+            &metrics.enobufs_resends,
+        ),
+        (
+            "ekho_decrypt_failures_total",
+            "Packets that failed to decrypt and were echoed back.",
+            &metrics.decrypt_failures,
+        ),
+        (
+            "ekho_kcp_resends_total",
+            "KCP segments retransmitted.",
+            &metrics.resends,
+        ),
+        (
+            "ekho_kcp_dead_links_total",
+            "Sessions torn down over a dead link.",
+            &metrics.dead_links,
+        ),
+    ]
+    .iter()
+    {
+        render_counter(&mut out, name, help, counter.get());
+    }
+
+    let _ = writeln!(
+        out,
+        "# HELP ekho_socks_requests_total SOCKS5 requests by outcome."
+    );
+    let _ = writeln!(out, "# TYPE ekho_socks_requests_total counter");
+    for (outcome, label) in OUTCOMES.iter() {
+        let _ = writeln!(
+            out,
+            "ekho_socks_requests_total{{outcome=\"{}\"}} {}",
+            label,
+            metrics.socks_requests[*outcome as usize].get()
+        );
+    }
+
+    metrics.srtt.render(
+        &mut out,
+        "ekho_kcp_srtt_seconds",
+        "Smoothed RTT of sessions, sampled every second.",
+    );
+    metrics.rto.render(
+        &mut out,
+        "ekho_kcp_rto_seconds",
+        "Retransmission timeout of sessions, sampled every second.",
+    );
+
+    let controls: Vec<_> = ekho
+        .controls()
+        .iter()
+        .filter_map(|entry| entry.value().upgrade())
+        .collect();
+    let mut rate = 0.0;
+    let mut phases = [
+        ("starting", 0),
+        ("decision_making", 0),
+        ("rate_adjusting", 0),
+    ];
+    for control in &controls {
+        let stats = session::lock(control).await.stats();
+        rate += stats.send_rate.unwrap_or_default();
+        if let Some(phase) = stats.pcc_phase {
+            if let Some(entry) = phases.iter_mut().find(|(name, _)| *name == phase) {
+                entry.1 += 1;
+            }
+        }
+    }
+    let _ = writeln!(out, "# HELP ekho_sessions Live sessions.");
+    let _ = writeln!(out, "# TYPE ekho_sessions gauge");
+    let _ = writeln!(out, "ekho_sessions {}", controls.len());
+    let _ = writeln!(
+        out,
+        "# HELP ekho_pcc_rate_kilobytes_per_second Sum of the PCC sending rates of live sessions."
+    );
+    let _ = writeln!(out, "# TYPE ekho_pcc_rate_kilobytes_per_second gauge");
+    let _ = writeln!(out, "ekho_pcc_rate_kilobytes_per_second {}", rate);
+    let _ = writeln!(out, "# HELP ekho_pcc_sessions Live sessions by PCC phase.");
+    let _ = writeln!(out, "# TYPE ekho_pcc_sessions gauge");
+    for (phase, count) in phases.iter() {
+        let _ = writeln!(out, "ekho_pcc_sessions{{phase=\"{}\"}} {}", phase, count);
+    }
+    out
+}
+
+async fn handle_scrape(ekho: &Ekho, mut stream: TcpStream) -> Result<()> {
+    let mut buf = vec![0; 4096];
+    let mut len = 0;
+    while !buf[..len].windows(4).any(|w| w == b"\r\n\r\n") {
+        if len == buf.len() {
+            return Ok(());
+        }
+        match stream.read(&mut buf[len..]).await? {
+            0 => return Ok(()),
+            n => len += n,
+        }
+    }
+    let response = if buf.starts_with(b"GET /metrics ") {
+        let body = render(ekho).await;
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        )
+    } else {
+        String::from("HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+    };
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// Binds the configured endpoint, if any, and serves the metrics of `ekho` on it in the
+/// background.
+pub async fn start(ekho: &Ekho) -> Result<()> {
+    let address = match ekho.config().metrics.listen {
+        Some(address) => address,
+        None => return Ok(()),
+    };
+    let listener = TcpListener::bind(address)
+        .await
+        .with_context(|| format!("binding metrics endpoint on {}", address))?;
+    info!("serving metrics on {}", address);
+    task::spawn(serve(ekho.clone(), listener));
+    Ok(())
+}
+
+async fn serve(ekho: Ekho, listener: TcpListener) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                let ekho = ekho.clone();
+                task::spawn(async move {
+                    if let Err(err) = handle_scrape(&ekho, stream).await {
+                        debug!("error serving metrics: {}", err);
+                    }
+                });
+            }
+            Err(err) => error!("error accepting metrics connection: {}", err),
+        }
+    }
+}
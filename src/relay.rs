@@ -19,15 +19,105 @@ CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
 
 #![allow(clippy::needless_lifetimes)]
 
-use crate::config::config;
+//! Relaying between a local TCP connection and either another TCP connection or a session.
+//!
+//! The two directions are relayed independently: when one of them reaches the end of stream, the
+//! end is propagated (`shutdown` on the TCP write half, an empty message on sessions) and the other
+//! direction keeps going. The relay finishes once both directions have, or once it has been idle for
+//! [idle_timeout](Config::idle_timeout) ([half_close_timeout](Config::half_close_timeout) after one
+//! direction has finished).
+
 use crate::session::Session;
-use anyhow::Result;
+use anyhow::{bail, Result};
+use derivative::Derivative;
+use parking_lot::Mutex as SyncMutex;
+use serde::{Deserialize, Serialize};
+use socket2::SockRef;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::io::{Error, ErrorKind};
 use tokio::net::tcp::{ReadHalf, WriteHalf};
 use tokio::net::TcpStream;
-use tokio::select;
-use tracing::info;
+use tokio::time::{sleep, sleep_until, Duration};
+use tokio::{select, try_join};
+use tracing::{debug, info};
+
+/// Relay configuration.
+#[derive(Clone, Debug, Deserialize, Serialize, Derivative)]
+#[derivative(Default)]
+#[serde(default)]
+pub struct Config {
+    /// A relay without any traffic for this long (unit: s) is torn down. 0 means relays never
+    /// time out.
+    #[derivative(Default(value = "0"))]
+    pub idle_timeout: u64,
+    /// Once one direction has finished, the relay is torn down after the other one has been idle
+    /// for this long (unit: s). 0 means half-closed relays never time out.
+    #[derivative(Default(value = "60"))]
+    pub half_close_timeout: u64,
+}
+
+/// How often a relay without timeouts checks whether they apply again.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Tracks the progress of both directions of a relay.
+struct Activity {
+    last: SyncMutex<Instant>,
+    half_closed: AtomicBool,
+    idle_timeout: u64,
+    half_close_timeout: u64,
+}
+
+impl Activity {
+    fn new(config: &Config) -> Self {
+        Activity {
+            last: SyncMutex::new(Instant::now()),
+            half_closed: AtomicBool::new(false),
+            idle_timeout: config.idle_timeout,
+            half_close_timeout: config.half_close_timeout,
+        }
+    }
+
+    fn touch(&self) {
+        *self.last.lock() = Instant::now();
+    }
+
+    fn half_close(&self) {
+        self.touch();
+        self.half_closed.store(true, Ordering::SeqCst);
+    }
+
+    /// The idle timeout applying at the moment.
+    fn timeout(&self) -> Option<Duration> {
+        let timeout = if self.half_closed.load(Ordering::SeqCst) {
+            self.half_close_timeout
+        } else {
+            self.idle_timeout
+        };
+        if timeout > 0 {
+            Some(Duration::from_secs(timeout))
+        } else {
+            None
+        }
+    }
+
+    /// Returns once the relay has been idle for longer than it may.
+    async fn idle(&self) {
+        loop {
+            match self.timeout() {
+                Some(timeout) => {
+                    let deadline = *self.last.lock() + timeout;
+                    sleep_until(deadline.into()).await
+                }
+                None => sleep(POLL_INTERVAL).await,
+            }
+            if matches!(self.timeout(), Some(timeout) if self.last.lock().elapsed() >= timeout) {
+                return;
+            }
+        }
+    }
+}
 
 fn handle_io_error(err: Error) -> Result<()> {
     if matches!(
@@ -41,7 +131,19 @@ fn handle_io_error(err: Error) -> Result<()> {
     }
 }
 
-async fn forward_tcp<'a>(mut from: ReadHalf<'a>, mut to: WriteHalf<'a>) -> Result<()> {
+/// Propagates the end of stream to a TCP connection, which may already be gone.
+async fn shutdown_tcp<'a>(to: &mut WriteHalf<'a>) -> Result<()> {
+    match to.shutdown().await {
+        Err(err) if err.kind() != ErrorKind::NotConnected => handle_io_error(err),
+        _ => Ok(()),
+    }
+}
+
+async fn forward_tcp<'a>(
+    mut from: ReadHalf<'a>,
+    mut to: WriteHalf<'a>,
+    activity: &Activity,
+) -> Result<()> {
     let mut buf = [0; 1024];
     loop {
         match from.read(&mut buf).await {
@@ -49,11 +151,14 @@ async fn forward_tcp<'a>(mut from: ReadHalf<'a>, mut to: WriteHalf<'a>) -> Resul
             Ok(len) => to.write_all(&buf[..len]).await?,
             Err(err) => handle_io_error(err)?,
         }
+        activity.touch();
     }
+    shutdown_tcp(&mut to).await?;
+    activity.half_close();
     Ok(())
 }
 
-pub async fn relay_tcp(mut a: TcpStream, mut b: TcpStream) -> Result<()> {
+pub async fn relay_tcp(mut a: TcpStream, mut b: TcpStream, config: &Config) -> Result<()> {
     info!(
         "relaying between {:?} and {:?}",
         a.peer_addr()?,
@@ -61,14 +166,27 @@ pub async fn relay_tcp(mut a: TcpStream, mut b: TcpStream) -> Result<()> {
     );
     let (a_read, a_write) = a.split();
     let (b_read, b_write) = b.split();
+    let activity = Activity::new(config);
     select! {
-        res = forward_tcp(a_read, b_write) => res,
-        res = forward_tcp(b_read, a_write) => res
+        res = async {
+            try_join!(
+                forward_tcp(a_read, b_write, &activity),
+                forward_tcp(b_read, a_write, &activity)
+            )
+        } => res.map(|_| ()),
+        _ = activity.idle() => {
+            debug!("relay idle for too long");
+            Ok(())
+        }
     }
 }
 
-async fn forward_tcp_to_kcp<'a>(mut from: ReadHalf<'a>, to: &Session) -> Result<()> {
-    let mut buf = vec![0; config().kcp.mss()];
+async fn forward_tcp_to_kcp<'a>(
+    mut from: ReadHalf<'a>,
+    to: &Session,
+    activity: &Activity,
+) -> Result<()> {
+    let mut buf = vec![0; to.ekho().config().kcp.mss()];
     loop {
         match from.read(&mut buf).await {
             Ok(0) => break,
@@ -76,31 +194,64 @@ async fn forward_tcp_to_kcp<'a>(mut from: ReadHalf<'a>, to: &Session) -> Result<
             Err(err) if err.kind() == ErrorKind::ConnectionAborted => break,
             Err(err) => handle_io_error(err)?,
         }
+        activity.touch();
     }
+    to.shutdown().await;
+    activity.half_close();
     Ok(())
 }
 
-async fn forward_kcp_to_tcp<'a>(from: &Session, mut to: WriteHalf<'a>) -> Result<()> {
+async fn forward_kcp_to_tcp<'a>(
+    from: &Session,
+    mut to: WriteHalf<'a>,
+    activity: &Activity,
+) -> Result<()> {
     loop {
         let buf = from.recv().await;
         if buf.is_empty() {
+            if from.is_reset().await {
+                bail!("session reset by peer");
+            }
             break;
         }
         match to.write_all(&buf).await {
-            Ok(()) => continue,
+            Ok(()) => {}
             Err(err) if err.kind() == ErrorKind::ConnectionAborted => break,
             Err(err) => handle_io_error(err)?,
         }
+        activity.touch();
     }
+    shutdown_tcp(&mut to).await?;
+    activity.half_close();
     Ok(())
 }
 
 pub async fn relay_kcp(mut tcp: TcpStream, session: Session) -> Result<()> {
     let (read, write) = tcp.split();
-    let res = select! {
-        res = forward_tcp_to_kcp(read, &session) => res,
-        res = forward_kcp_to_tcp(&session, write) => res
+    let activity = Activity::new(&session.ekho().config().relay);
+    let (res, abort) = select! {
+        res = async {
+            try_join!(
+                forward_tcp_to_kcp(read, &session, &activity),
+                forward_kcp_to_tcp(&session, write, &activity)
+            )
+        } => {
+            let abort = res.is_err();
+            (res.map(|_| ()), abort)
+        }
+        _ = activity.idle() => {
+            debug!("relay idle for too long");
+            (Ok(()), true)
+        }
     };
-    session.close().await;
+    if abort {
+        // Pass the abort on: a reset session resets the TCP connection and vice versa
+        if session.is_reset().await {
+            SockRef::from(&tcp).set_linger(Some(Duration::from_secs(0)))?;
+        }
+        session.reset().await;
+    } else {
+        session.close().await;
+    }
     res
 }
@@ -0,0 +1,518 @@
+/*
+Copyright 2021 Chengyuan Ma
+
+Permission is hereby granted, free of charge, to any person obtaining a copy of this software and
+associated documentation files (the "Software"), to deal in the Software without restriction,
+including without limitation the rights to use, copy, modify, merge, publish, distribute, sub-
+-license, and/or sell copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all copies or substantial
+portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING BUT
+NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NON-
+-INFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES
+OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+*/
+
+//! Rule-based routing of client requests.
+//!
+//! Rules are tried in order and the first matching one decides whether a destination is connected
+//! directly, through the tunnel, or rejected. Within a rule, the destination matches if it matches
+//! any of the address conditions (`cidr`, `domain`, `domain_suffix`, `domain_keyword`) and any of
+//! the `port` conditions; omitted conditions match everything.
+//!
+//! ```toml
+//! [routing]
+//! default = "proxy"
+//!
+//! [[routing.rules]]
+//! cidr = ["10.0.0.0/8", "192.168.0.0/16", "fd00::/8"]
+//! domain_suffix = ["corp.example.com"]
+//! action = "direct"
+//!
+//! [[routing.rules]]
+//! domain_keyword = ["ads"]
+//! port = [80, "8000-8080"]
+//! action = "reject"
+//! ```
+//!
+//! Hostnames are never resolved for routing, so CIDR rules only apply to requests that carry an
+//! IP address.
+
+use crate::socks5::{Socks5Addr, Socks5SocketAddr};
+use serde::de::{Error as _, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum ParseError {
+    #[error("invalid CIDR: {0}")]
+    InvalidCidr(String),
+    #[error("invalid port range: {0}")]
+    InvalidPortRange(String),
+}
+
+/// What to do with a request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    /// Connect to the destination from the client itself.
+    Direct,
+    /// Connect to the destination through the server.
+    Proxy,
+    /// Refuse the request.
+    Reject,
+}
+
+/// Routing configuration.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Config {
+    /// The action taken when no rule matches.
+    pub default: Action,
+    pub rules: Vec<Rule>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            default: Action::Proxy,
+            rules: Vec::new(),
+        }
+    }
+}
+
+impl Config {
+    /// Decides what to do with a request to `dst`.
+    pub fn route(&self, dst: &Socks5SocketAddr) -> Action {
+        self.rules
+            .iter()
+            .find(|rule| rule.matches(dst))
+            .map_or(self.default, |rule| rule.action)
+    }
+}
+
+/// A single routing rule.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Rule {
+    #[serde(default)]
+    pub cidr: Vec<Cidr>,
+    /// Matches the hostname exactly.
+    #[serde(default)]
+    pub domain: Vec<String>,
+    /// Matches the hostname itself and all of its subdomains.
+    #[serde(default)]
+    pub domain_suffix: Vec<String>,
+    /// Matches hostnames containing the keyword.
+    #[serde(default)]
+    pub domain_keyword: Vec<String>,
+    #[serde(default)]
+    pub port: Vec<PortRange>,
+    pub action: Action,
+}
+
+impl Rule {
+    fn matches_addr(&self, addr: &Socks5Addr) -> bool {
+        if self.cidr.is_empty()
+            && self.domain.is_empty()
+            && self.domain_suffix.is_empty()
+            && self.domain_keyword.is_empty()
+        {
+            return true;
+        }
+        match addr {
+            Socks5Addr::Ip(ip) => self.cidr.iter().any(|cidr| cidr.contains(*ip)),
+            Socks5Addr::Hostname(hostname) => {
+                let hostname = hostname.trim_end_matches('.').to_ascii_lowercase();
+                self.domain
+                    .iter()
+                    .any(|domain| domain.eq_ignore_ascii_case(&hostname))
+                    || self.domain_suffix.iter().any(|suffix| {
+                        let suffix = suffix.trim_start_matches('.').to_ascii_lowercase();
+                        hostname == suffix || hostname.ends_with(&format!(".{}", suffix))
+                    })
+                    || self
+                        .domain_keyword
+                        .iter()
+                        .any(|keyword| hostname.contains(&keyword.to_ascii_lowercase()))
+            }
+        }
+    }
+
+    fn matches(&self, dst: &Socks5SocketAddr) -> bool {
+        (self.port.is_empty() || self.port.iter().any(|range| range.contains(dst.port)))
+            && self.matches_addr(&dst.addr)
+    }
+}
+
+/// An IPv4 or IPv6 network in CIDR notation, e.g. `10.0.0.0/8`. A bare address is treated as a
+/// network containing only that address.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u32,
+}
+
+impl Cidr {
+    pub fn addr(&self) -> IpAddr {
+        self.addr
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        fn prefix_eq(a: u128, b: u128, prefix: u32, bits: u32) -> bool {
+            prefix == 0 || (a ^ b) >> (bits - prefix) == 0
+        }
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                prefix_eq(u32::from(net).into(), u32::from(ip).into(), self.prefix, 32)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                prefix_eq(u128::from(net), u128::from(ip), self.prefix, 128)
+            }
+            // IPv4-mapped IPv6 addresses are matched against IPv4 networks
+            (IpAddr::V4(_), IpAddr::V6(ip)) => match ip.to_ipv4_mapped() {
+                Some(ip) => self.contains(IpAddr::V4(ip)),
+                None => false,
+            },
+            (IpAddr::V6(_), IpAddr::V4(_)) => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || ParseError::InvalidCidr(s.into());
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| invalid())?;
+        let bits = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse().map_err(|_| invalid())?,
+            None => bits,
+        };
+        if prefix > bits {
+            return Err(invalid());
+        }
+        Ok(Cidr { addr, prefix })
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+impl Serialize for Cidr {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        s.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Cidr {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        String::deserialize(d)?.parse().map_err(D::Error::custom)
+    }
+}
+
+/// An inclusive range of ports, written either as a single port or as `low-high`.
+#[derive(Clone, Copy, Debug)]
+pub struct PortRange(pub u16, pub u16);
+
+impl PortRange {
+    pub fn contains(&self, port: u16) -> bool {
+        self.0 <= port && port <= self.1
+    }
+}
+
+impl FromStr for PortRange {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || ParseError::InvalidPortRange(s.into());
+        let (low, high) = s.split_once('-').unwrap_or((s, s));
+        let low = low.trim().parse().map_err(|_| invalid())?;
+        let high = high.trim().parse().map_err(|_| invalid())?;
+        if low > high {
+            return Err(invalid());
+        }
+        Ok(PortRange(low, high))
+    }
+}
+
+impl fmt::Display for PortRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0 == self.1 {
+            write!(f, "{}", self.0)
+        } else {
+            write!(f, "{}-{}", self.0, self.1)
+        }
+    }
+}
+
+impl Serialize for PortRange {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        if self.0 == self.1 {
+            s.serialize_u16(self.0)
+        } else {
+            s.collect_str(self)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for PortRange {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        struct PortRangeVisitor;
+        impl<'de> Visitor<'de> for PortRangeVisitor {
+            type Value = PortRange;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                write!(formatter, "a port number or a string like \"8000-8080\"")
+            }
+
+            fn visit_i64<E: serde::de::Error>(self, v: i64) -> Result<Self::Value, E> {
+                self.visit_str(&v.to_string())
+            }
+
+            fn visit_u64<E: serde::de::Error>(self, v: u64) -> Result<Self::Value, E> {
+                self.visit_str(&v.to_string())
+            }
+
+            fn visit_str<E: serde::de::Error>(self, v: &str) -> Result<Self::Value, E> {
+                v.parse().map_err(E::custom)
+            }
+        }
+        d.deserialize_any(PortRangeVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cidr(s: &str) -> Cidr {
+        s.parse().unwrap()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn dst(s: &str) -> Socks5SocketAddr {
+        s.parse().unwrap()
+    }
+
+    fn config(s: &str) -> Config {
+        toml::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn cidr_v4() {
+        let net = cidr("192.168.0.0/16");
+        assert!(net.contains(ip("192.168.0.0")));
+        assert!(net.contains(ip("192.168.255.255")));
+        assert!(!net.contains(ip("192.169.0.0")));
+        assert!(!net.contains(ip("192.167.255.255")));
+        assert!(net.contains(ip("::ffff:192.168.1.1")));
+        assert!(!net.contains(ip("::192.168.1.1")));
+    }
+
+    #[test]
+    fn cidr_boundaries() {
+        let all = cidr("0.0.0.0/0");
+        assert!(all.contains(ip("0.0.0.0")));
+        assert!(all.contains(ip("255.255.255.255")));
+        assert!(!all.contains(ip("::1")));
+
+        let host = cidr("10.0.0.1/32");
+        assert!(host.contains(ip("10.0.0.1")));
+        assert!(!host.contains(ip("10.0.0.0")));
+        assert!(!host.contains(ip("10.0.0.2")));
+        assert_eq!(cidr("10.0.0.1"), host);
+
+        assert!(cidr("::/0").contains(ip("ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff")));
+        assert!(!cidr("::/0").contains(ip("127.0.0.1")));
+        assert!(cidr("::1/128").contains(ip("::1")));
+        assert!(!cidr("::1/128").contains(ip("::2")));
+        assert_eq!(cidr("::1"), cidr("::1/128"));
+    }
+
+    #[test]
+    fn cidr_v6() {
+        let net = cidr("fd00::/8");
+        assert!(net.contains(ip("fd00::")));
+        assert!(net.contains(ip("fdff:ffff:ffff:ffff:ffff:ffff:ffff:ffff")));
+        assert!(!net.contains(ip("fe00::")));
+        assert!(!net.contains(ip("fc00::1")));
+        assert!(!net.contains(ip("10.0.0.1")));
+
+        let net = cidr("2001:db8::/33");
+        assert!(net.contains(ip("2001:db8:7fff::1")));
+        assert!(!net.contains(ip("2001:db8:8000::")));
+    }
+
+    #[test]
+    fn cidr_parse() {
+        assert_eq!(cidr("10.0.0.0/8").to_string(), "10.0.0.0/8");
+        assert_eq!(cidr("fd00::/8").to_string(), "fd00::/8");
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("::/129".parse::<Cidr>().is_err());
+        assert!("10.0.0.0/".parse::<Cidr>().is_err());
+        assert!("10.0.0/8".parse::<Cidr>().is_err());
+        assert!("example.com/8".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn port_range() {
+        let range: PortRange = "8000-8080".parse().unwrap();
+        assert!(!range.contains(7999));
+        assert!(range.contains(8000));
+        assert!(range.contains(8080));
+        assert!(!range.contains(8081));
+        assert_eq!(range.to_string(), "8000-8080");
+
+        let single: PortRange = "443".parse().unwrap();
+        assert!(single.contains(443));
+        assert!(!single.contains(442));
+        assert!(!single.contains(444));
+        assert_eq!(single.to_string(), "443");
+
+        let all: PortRange = "0-65535".parse().unwrap();
+        assert!(all.contains(0));
+        assert!(all.contains(u16::MAX));
+
+        assert!(" 80 - 90 ".parse::<PortRange>().is_ok());
+        assert!("90-80".parse::<PortRange>().is_err());
+        assert!("65536".parse::<PortRange>().is_err());
+        assert!("-80".parse::<PortRange>().is_err());
+        assert!("http".parse::<PortRange>().is_err());
+    }
+
+    #[test]
+    fn port_range_deserialize() {
+        let config = config(
+            r#"
+            [[rules]]
+            port = [80, "8000-8080"]
+            action = "reject"
+            "#,
+        );
+        assert_eq!(config.route(&dst("example.com:80")), Action::Reject);
+        assert_eq!(config.route(&dst("example.com:8080")), Action::Reject);
+        assert_eq!(config.route(&dst("example.com:81")), Action::Proxy);
+        assert!(
+            toml::from_str::<Config>("[[rules]]\nport = [\"x\"]\naction = \"reject\"").is_err()
+        );
+    }
+
+    #[test]
+    fn domain_exact_and_suffix() {
+        let config = config(
+            r#"
+            [[rules]]
+            domain = ["example.com"]
+            action = "direct"
+
+            [[rules]]
+            domain_suffix = [".example.org"]
+            action = "reject"
+            "#,
+        );
+        assert_eq!(config.route(&dst("example.com:443")), Action::Direct);
+        assert_eq!(config.route(&dst("EXAMPLE.com.:443")), Action::Direct);
+        assert_eq!(config.route(&dst("www.example.com:443")), Action::Proxy);
+        assert_eq!(config.route(&dst("notexample.com:443")), Action::Proxy);
+
+        assert_eq!(config.route(&dst("example.org:443")), Action::Reject);
+        assert_eq!(config.route(&dst("a.b.Example.org:443")), Action::Reject);
+        assert_eq!(config.route(&dst("notexample.org:443")), Action::Proxy);
+        assert_eq!(config.route(&dst("example.org.evil:443")), Action::Proxy);
+    }
+
+    #[test]
+    fn domain_keyword() {
+        let config = config(
+            r#"
+            [[rules]]
+            domain_keyword = ["Ads"]
+            action = "reject"
+            "#,
+        );
+        assert_eq!(config.route(&dst("ads.example.com:80")), Action::Reject);
+        assert_eq!(config.route(&dst("myadserver.net:80")), Action::Reject);
+        assert_eq!(config.route(&dst("example.com:80")), Action::Proxy);
+    }
+
+    #[test]
+    fn address_and_port() {
+        let config = config(
+            r#"
+            [[rules]]
+            cidr = ["10.0.0.0/8"]
+            domain_suffix = ["lan"]
+            port = [22, "80-90"]
+            action = "direct"
+            "#,
+        );
+        assert_eq!(config.route(&dst("10.1.2.3:22")), Action::Direct);
+        assert_eq!(config.route(&dst("nas.lan:85")), Action::Direct);
+        assert_eq!(config.route(&dst("10.1.2.3:443")), Action::Proxy);
+        assert_eq!(config.route(&dst("11.1.2.3:22")), Action::Proxy);
+        // Hostnames are not resolved, so they never match CIDR conditions
+        assert_eq!(config.route(&dst("localhost:22")), Action::Proxy);
+    }
+
+    #[test]
+    fn rule_order() {
+        let config = config(
+            r#"
+            default = "direct"
+
+            [[rules]]
+            domain = ["blocked.example.com"]
+            action = "reject"
+
+            [[rules]]
+            domain_suffix = ["example.com"]
+            action = "proxy"
+
+            [[rules]]
+            domain = ["blocked.example.com"]
+            action = "direct"
+            "#,
+        );
+        assert_eq!(
+            config.route(&dst("blocked.example.com:443")),
+            Action::Reject
+        );
+        assert_eq!(config.route(&dst("www.example.com:443")), Action::Proxy);
+        assert_eq!(config.route(&dst("example.net:443")), Action::Direct);
+        assert_eq!(config.route(&dst("[::1]:443")), Action::Direct);
+    }
+
+    #[test]
+    fn empty_rule_matches_everything() {
+        let config = config(
+            r#"
+            [[rules]]
+            action = "reject"
+
+            [[rules]]
+            cidr = ["0.0.0.0/0"]
+            action = "direct"
+            "#,
+        );
+        assert_eq!(config.route(&dst("192.0.2.1:80")), Action::Reject);
+        assert_eq!(config.route(&dst("example.com:80")), Action::Reject);
+    }
+}
@@ -1,7 +1,15 @@
+use crate::ekho::Ekho;
+use crate::forward::serve_remote_forward;
 use crate::relay::relay_kcp;
 use crate::session::Session;
-use crate::socks5::{Socks5Command, Socks5Error, Socks5Reply, Socks5Request};
+use crate::socks5::{Socks5Addr, Socks5Command, Socks5Reply, Socks5Request, Socks5SocketAddr};
+use crate::tun;
+use crate::udp::relay_udp_remote;
 use anyhow::Result;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::select;
 use tokio::task;
 use tracing::{debug, error, instrument};
 
@@ -33,20 +41,123 @@ async fn handle_request(session: Session) -> Result<()> {
                 session.close().await;
             }
         },
-        _ => {
-            session
-                .send(&Socks5Reply::Error(Socks5Error::CommandNotSupported).marshal())
-                .await;
-            session.close().await;
-        }
+        Socks5Command::UdpAssociate => match UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await {
+            Ok(socket) => {
+                session
+                    .send(
+                        &Socks5Reply::Success {
+                            bnd: socket.local_addr()?.into(),
+                        }
+                        .marshal(),
+                    )
+                    .await;
+                relay_udp_remote(socket, session).await?;
+            }
+            Err(err) => {
+                error!("error while binding UDP relay socket: {}", err);
+                session
+                    .send(&Socks5Reply::Error(err.into()).marshal())
+                    .await;
+                session.close().await;
+            }
+        },
+        Socks5Command::Bind => match bind_listener(&request.dst).await {
+            Ok(listener) => {
+                session
+                    .send(
+                        &Socks5Reply::Success {
+                            bnd: listener.local_addr()?.into(),
+                        }
+                        .marshal(),
+                    )
+                    .await;
+                // Anything coming from the client at this point means it has given up waiting.
+                let accepted = select! {
+                    res = accept_from(&listener, &request.dst) => Some(res),
+                    _ = session.recv() => None,
+                };
+                drop(listener);
+                match accepted {
+                    Some(Ok((remote, peer))) => {
+                        session
+                            .send(&Socks5Reply::Success { bnd: peer.into() }.marshal())
+                            .await;
+                        relay_kcp(remote, session).await?;
+                    }
+                    Some(Err(err)) => {
+                        error!(
+                            "error while accepting connection from {}: {}",
+                            request.dst, err
+                        );
+                        session
+                            .send(&Socks5Reply::Error(err.into()).marshal())
+                            .await;
+                        session.close().await;
+                    }
+                    None => session.close().await,
+                }
+            }
+            Err(err) => {
+                error!("error while binding listener for {}: {}", request.dst, err);
+                session
+                    .send(&Socks5Reply::Error(err.into()).marshal())
+                    .await;
+                session.close().await;
+            }
+        },
+        Socks5Command::RemoteForward => serve_remote_forward(session, request).await?,
+        Socks5Command::Tun => tun::serve(session).await?,
     }
     Ok(())
 }
 
+/// Binds a listener for the BIND command on the local address used to reach `dst`, so that the
+/// address reported back to the client is one that `dst` can actually connect to. The listener is
+/// bound to all addresses if `dst` is unspecified, or if it would otherwise only be reachable over
+/// loopback by a host that is not.
+async fn bind_listener(dst: &Socks5SocketAddr) -> io::Result<TcpListener> {
+    let dst = dst.resolve().await?;
+    let unspecified: IpAddr = match dst {
+        SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    };
+    if dst.ip().is_unspecified() {
+        return TcpListener::bind((unspecified, 0)).await;
+    }
+    // Connecting a UDP socket sends nothing but makes the OS pick the outgoing interface. Only the
+    // IP matters for routing, and DST.PORT is often left as 0 in BIND requests.
+    let probe = async {
+        let socket = UdpSocket::bind((unspecified, 0)).await?;
+        socket.connect((dst.ip(), 9 /* discard */)).await?;
+        socket.local_addr()
+    };
+    let ip = match probe.await {
+        Ok(addr) if !addr.ip().is_loopback() || dst.ip().is_loopback() => addr.ip(),
+        _ => unspecified,
+    };
+    TcpListener::bind((ip, 0)).await
+}
+
+/// Accepts the first incoming connection from the host the client told us to expect.
+async fn accept_from(
+    listener: &TcpListener,
+    expected: &Socks5SocketAddr,
+) -> io::Result<(TcpStream, SocketAddr)> {
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        match expected.addr {
+            Socks5Addr::Ip(ip) if !ip.is_unspecified() && ip != peer.ip() => {
+                debug!("rejecting connection from unexpected peer {}", peer)
+            }
+            _ => return Ok((stream, peer)),
+        }
+    }
+}
+
 #[instrument]
-pub async fn run() {
+pub async fn run(ekho: Ekho) {
     loop {
-        let kcp = Session::incoming().await;
+        let kcp = ekho.accept().await;
         task::spawn(async move {
             if let Err(err) = handle_request(kcp).await {
                 error!("{}", err);
@@ -20,212 +20,378 @@ CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
 //! Build sessions above the raw KCP algorithm
 
 #![allow(dead_code)]
-use crate::config::config;
+use crate::capture::Direction;
+use crate::ekho::Ekho;
 use crate::icmp::Endpoint;
 
-use crate::kcp::{ControlBlock, Error};
-use chacha20poly1305::aead::{AeadInPlace, NewAead};
-use chacha20poly1305::{ChaCha20Poly1305, Nonce};
-use dashmap::DashMap;
+use crate::kcp::{ControlBlock, Error, State, Stats};
+use chacha20poly1305::aead::AeadInPlace;
+use chacha20poly1305::Nonce;
+use derivative::Derivative;
 use lazy_static::lazy_static;
 use rand::{thread_rng, Rng};
-use rustc_hash::FxHasher;
+use serde::{Deserialize, Serialize};
 use std::fmt;
-use std::hash::BuildHasherDefault;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{Arc, Weak};
-use tokio::select;
-use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
-use tokio::sync::{Mutex, Notify};
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::sync::{Mutex, MutexGuard, Notify};
 use tokio::task;
 use tokio::task::JoinHandle;
-use tokio::time::{interval, sleep, Duration};
+use tokio::time::{interval, timeout, Duration};
 use tracing::{debug, debug_span, error, instrument, warn};
 use tracing_futures::Instrument;
 
-type Control = (Mutex<ControlBlock>, Notify);
+/// A KCP control block, the notification of its progress, and the epoch of its clock.
+pub(crate) type Control = (Mutex<ControlBlock>, Notify, Instant);
 
 lazy_static! {
-    static ref CONTROLS: DashMap<(Endpoint, u32), Weak<Control>, BuildHasherDefault<FxHasher>> =
-        Default::default();
-    static ref CIPHER: ChaCha20Poly1305 = ChaCha20Poly1305::new(&config().key);
     static ref NONCE: Nonce = Nonce::default();
-    static ref INCOMING: (UnboundedSender<Session>, Mutex<UnboundedReceiver<Session>>) = {
-        let (tx, rx) = unbounded_channel();
-        (tx, Mutex::new(rx))
-    };
 }
 
 const CLOSE_TIMEOUT: Duration = Duration::from_secs(60);
+/// How often the RTT and RTO of each session are recorded in the metrics.
+const METRICS_SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Session configuration.
+#[derive(Clone, Debug, Deserialize, Serialize, Derivative)]
+#[derivative(Default)]
+#[serde(default)]
+pub struct Config {
+    /// A session without any data sent or received for this long (unit: s) is torn down, so that
+    /// sessions the peer forgot about do not pile up. Keepalive probes count as activity, so with
+    /// [keepalive](crate::kcp::Config::keepalive) on, sessions whose peer is still there never time
+    /// out, even if they carry no data (e.g. remote forward registrations). 0 means sessions never
+    /// time out.
+    #[derivative(Default(value = "0"))]
+    pub idle_timeout: u64,
+}
+
+/// A live session, as listed by [Ekho::sessions].
+#[derive(Clone, Debug, Serialize)]
+pub struct SessionInfo {
+    pub peer: Endpoint,
+    pub conv: u32,
+    /// Time since the session was opened (unit: s).
+    pub age: f64,
+    pub stats: Stats,
+}
 
 /// A session, built on top of KCP
 pub struct Session {
+    ekho: Ekho,
     conv: u32,
     peer: Endpoint,
     updater: JoinHandle<()>,
     control: Arc<Control>,
-    peer_closing: Arc<AtomicBool>,
-    local_closing: Arc<AtomicBool>,
+    /// Whether the updater has stopped, after which nothing can be sent or received any more.
+    terminated: Arc<AtomicBool>,
+    /// Whether the session has been closed or reset explicitly; otherwise dropping it resets it.
+    finished: bool,
 }
 
 impl Session {
-    /// Creates a new session given a peer endpoint and a conv.
-    pub fn new(peer: Endpoint, conv: u32) -> Self {
-        assert!(!CONTROLS.contains_key(&(peer, conv)));
+    /// Creates a new session of `ekho` given a peer endpoint and a conv.
+    pub fn new(ekho: Ekho, peer: Endpoint, conv: u32) -> Self {
+        assert!(!ekho.controls().contains_key(&(peer, conv)));
         // The naming here is very nasty!
         let control = Arc::new((
-            Mutex::new(ControlBlock::new(conv, config().kcp.clone())),
+            Mutex::new(ControlBlock::new(conv, ekho.config().kcp.clone())),
             Notify::new(),
+            Instant::now(),
         ));
         let control_cloned = control.clone();
-        CONTROLS.insert((peer, conv), Arc::downgrade(&control_cloned));
-        let peer_closing = Arc::new(AtomicBool::new(false));
-        let local_closing = Arc::new(AtomicBool::new(false));
-        let peer_closing_cloned = peer_closing.clone();
-        let local_closing_cloned = local_closing.clone();
+        ekho.controls()
+            .insert((peer, conv), Arc::downgrade(&control_cloned));
+        let terminated = Arc::new(AtomicBool::new(false));
+        let terminated_cloned = terminated.clone();
+        let ekho_cloned = ekho.clone();
         let updater = task::spawn(
             async move {
-                let icmp_tx = crate::icmp::clone_sender().await;
-                let mut interval = interval(Duration::from_millis(config().kcp.interval as u64));
+                let ekho = ekho_cloned;
+                let icmp_tx = ekho.transport().sender();
+                let mut interval =
+                    interval(Duration::from_millis(ekho.config().kcp.interval as u64));
+                let idle_timeout = ekho.config().session.idle_timeout * 1000;
+                let mut resends = 0;
+                let mut last_sample = Instant::now();
                 'update_loop: loop {
                     {
                         interval.tick().await;
-                        let mut kcp = control_cloned.0.lock().await;
+                        let mut kcp = lock(&control_cloned).await;
                         kcp.flush();
                         control_cloned.1.notify_waiters();
                         while let Some(mut raw) = kcp.output() {
-                            // dissect_headers_from_raw(&raw, "send");
-                            if CIPHER.encrypt_in_place(&NONCE, b"", &mut raw).is_ok() {
+                            if let Some(capture) = ekho.capture() {
+                                capture.write(peer, Direction::Sent, &raw);
+                            }
+                            if ekho
+                                .cipher()
+                                .encrypt_in_place(&NONCE, b"", &mut raw)
+                                .is_ok()
+                            {
                                 icmp_tx.send((peer, raw)).await.unwrap();
                             } else {
                                 error!("error encrypting block");
                                 break 'update_loop;
                             }
                         }
-                        let peer_closing = peer_closing_cloned.load(Ordering::SeqCst);
-                        let local_closing = local_closing_cloned.load(Ordering::SeqCst);
-                        if kcp.dead_link() || peer_closing && local_closing && kcp.all_flushed() {
-                            if kcp.dead_link() {
-                                warn!("dead link");
-                            }
+                        let metrics = ekho.metrics();
+                        let stats = kcp.stats();
+                        let total = stats.segments_resent + stats.segments_fast_resent;
+                        metrics.resends.add(total - resends);
+                        resends = total;
+                        if last_sample.elapsed() >= METRICS_SAMPLE_INTERVAL {
+                            last_sample = Instant::now();
+                            metrics.srtt.observe(stats.srtt);
+                            metrics.rto.observe(stats.rto);
+                        }
+                        if kcp.dead_link() {
+                            warn!("dead link");
+                            metrics.dead_links.inc();
                             break;
                         }
+                        if idle_timeout > 0 && kcp.idle() as u64 >= idle_timeout {
+                            debug!("session idle for {} ms", kcp.idle());
+                            break;
+                        }
+                        match kcp.state() {
+                            State::Reset => break,
+                            State::Closed if kcp.all_flushed() => break,
+                            _ => {}
+                        }
                     }
                 }
+                ekho.controls().remove(&(peer, conv));
+                terminated_cloned.store(true, Ordering::SeqCst);
+                control_cloned.1.notify_waiters();
             }, // .instrument(debug_span!("update loop", ?peer, conv)),
         );
         Session {
+            ekho,
             conv,
             peer,
             control,
             updater,
-            peer_closing,
-            local_closing,
+            terminated,
+            finished: false,
         }
     }
 
-    pub fn connect(peer: Endpoint) -> Self {
+    /// Creates a new session of `ekho` to `peer` with a random conv.
+    pub fn connect(ekho: Ekho, peer: Endpoint) -> Self {
         loop {
             let conv = thread_rng().gen();
-            if !CONTROLS.contains_key(&(peer, conv)) {
-                return Session::new(peer, conv);
+            if !ekho.controls().contains_key(&(peer, conv)) {
+                return Session::new(ekho, peer, conv);
             }
         }
     }
 
-    pub async fn incoming() -> Self {
-        INCOMING.1.lock().await.recv().await.unwrap()
+    /// The Ekho instance this session belongs to.
+    pub fn ekho(&self) -> &Ekho {
+        &self.ekho
     }
 
+    pub fn peer(&self) -> Endpoint {
+        self.peer
+    }
+
+    /// Sends a message. Messages sent after [shutdown](Session::shutdown) are discarded.
     #[instrument(skip(buf))]
     pub async fn send(&self, buf: &[u8]) {
         loop {
+            let notified = self.control.1.notified();
             {
-                let mut kcp = self.control.0.lock().await;
+                let mut kcp = lock(&self.control).await;
+                if self.terminated.load(Ordering::SeqCst) {
+                    return;
+                }
                 if kcp.wait_send() < kcp.config().send_wnd as usize {
-                    if buf.is_empty() {
-                        self.local_closing.store(true, Ordering::SeqCst);
+                    match kcp.send(buf) {
+                        Err(Error::Closed) => debug!("discarding message sent after shutdown"),
+                        res => res.unwrap(),
                     }
-                    kcp.send(buf).unwrap();
                     break;
                 }
             }
-            self.control.1.notified().await;
+            notified.await;
         }
     }
 
+    /// Receives a message. An empty message means the end of stream, i.e. the peer has shut down
+    /// its sending direction, or the session has been reset or terminated (e.g. dead or idle).
     #[instrument]
     pub async fn recv(&self) -> Vec<u8> {
         loop {
+            let notified = self.control.1.notified();
             {
-                let mut kcp = self.control.0.lock().await;
+                let mut kcp = lock(&self.control).await;
                 match kcp.recv() {
-                    Ok(data) => {
-                        if data.is_empty() {
-                            self.peer_closing.store(true, Ordering::SeqCst);
-                        }
-                        return data;
+                    Ok(data) => return data,
+                    Err(Error::Closed) => return Vec::new(),
+                    Err(Error::NotAvailable) if self.terminated.load(Ordering::SeqCst) => {
+                        return Vec::new()
                     }
                     Err(Error::NotAvailable) => {}
-                    Err(err) => Err(err).unwrap(),
+                    Err(err) => {
+                        error!("error receiving: {}", err);
+                        return Vec::new();
+                    }
                 }
             }
-            self.control.1.notified().await;
+            notified.await;
+        }
+    }
+
+    /// Ends the sending direction of the session with a FIN, so that the peer's
+    /// [recv](Session::recv) returns an empty message once everything sent before has been
+    /// received. Receiving still works.
+    pub async fn shutdown(&self) {
+        lock(&self.control).await.shutdown();
+    }
+
+    /// A snapshot of the statistics of the underlying control block.
+    pub async fn stats(&self) -> Stats {
+        lock(&self.control).await.stats()
+    }
+
+    /// Whether the session has been reset by either side.
+    pub async fn is_reset(&self) -> bool {
+        lock(&self.control).await.state() == State::Reset
+    }
+
+    /// Sends an unreliable datagram, which must fit in a single KCP segment. Datagrams that cannot
+    /// be sent are silently dropped.
+    #[instrument(skip(buf))]
+    pub async fn send_datagram(&self, buf: &[u8]) {
+        let mut kcp = lock(&self.control).await;
+        if let Err(err) = kcp.send_datagram(buf) {
+            debug!("dropping datagram: {}", err);
         }
     }
 
+    /// Receives a datagram. This never returns once the session is terminated, so wait for
+    /// [recv](Session::recv) alongside to notice the end of the session.
     #[instrument]
-    pub async fn close(self) {
-        select! {
-            _ = sleep(CLOSE_TIMEOUT) => {}
-            _ = async {
-                self.send(b"").await;
-                while !self.peer_closing.load(Ordering::SeqCst) {
-                    let _discarded = self.recv().await;
+    pub async fn recv_datagram(&self) -> Vec<u8> {
+        loop {
+            let notified = self.control.1.notified();
+            {
+                let mut kcp = lock(&self.control).await;
+                if let Ok(data) = kcp.recv_datagram() {
+                    return data;
                 }
-                self.updater.await.unwrap();
-                CONTROLS.remove(&(self.peer, self.conv));
-                debug!("session closed, {} remaining", CONTROLS.len());
-            } => {}
+            }
+            notified.await;
+        }
+    }
+
+    /// Closes the session gracefully: shuts it down, discards whatever the peer still sends until
+    /// its FIN, and waits for everything to be acknowledged. Gives up with a reset after
+    /// `CLOSE_TIMEOUT`.
+    #[instrument]
+    pub async fn close(mut self) {
+        self.finished = true;
+        let graceful = async {
+            self.shutdown().await;
+            while !self.recv().await.is_empty() {}
+            (&mut self.updater).await.unwrap();
+        };
+        if timeout(CLOSE_TIMEOUT, graceful).await.is_err() {
+            warn!("session not closed in time, resetting");
+            lock(&self.control).await.reset();
+            (&mut self.updater).await.unwrap();
+        }
+        debug!("session closed, {} remaining", self.ekho.controls().len());
+    }
+
+    /// Aborts the session with a RST. The peer tears it down as soon as the RST arrives, without
+    /// waiting for anything in flight.
+    #[instrument]
+    pub async fn reset(mut self) {
+        self.finished = true;
+        lock(&self.control).await.reset();
+        (&mut self.updater).await.unwrap();
+        debug!("session reset, {} remaining", self.ekho.controls().len());
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        // Sessions dropped halfway (e.g. on errors) are reset, so that the peer does not wait on
+        // them. The updater sends the RST and stops on its own.
+        if !self.finished {
+            let control = self.control.clone();
+            task::spawn(async move { lock(&control).await.reset() });
         }
     }
 }
 
+/// Locks a control block, bringing its clock up to date.
+pub(crate) async fn lock(control: &Control) -> MutexGuard<'_, ControlBlock> {
+    let mut kcp = control.0.lock().await;
+    kcp.update(control.2.elapsed().as_millis() as u32);
+    kcp
+}
+
 impl fmt::Debug for Session {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "{}@{}", self.peer, self.conv)
     }
 }
 
+/// Hands packets received by the transport of `ekho` to their sessions, creating sessions for new
+/// conversations.
 #[instrument]
-async fn dispatch_loop() {
-    let sender = crate::icmp::clone_sender().await;
+pub(crate) async fn dispatch_loop(ekho: Ekho) {
+    let transport = ekho.transport();
+    let sender = transport.sender();
     loop {
-        let (from, mut raw) = crate::icmp::receive_packet()
+        let (from, mut raw) = transport
+            .receive_packet()
             .instrument(debug_span!("receive_icmp_packet"))
             .await;
-        if CIPHER.decrypt_in_place(&NONCE, b"", &mut raw).is_err() {
+        if ekho
+            .cipher()
+            .decrypt_in_place(&NONCE, b"", &mut raw)
+            .is_err()
+        {
+            ekho.metrics().decrypt_failures.inc();
             // Mimic real ping behavior
             sender.send((from, raw)).await.unwrap();
             continue;
         }
+        if raw.len() < 4 {
+            continue;
+        }
         let conv = crate::kcp::conv_from_raw(&raw);
         let key = &(from, conv);
-        let mut control = CONTROLS.get(key).and_then(|weak| weak.upgrade());
+        let mut control = ekho.controls().get(key).and_then(|weak| weak.upgrade());
         if control.is_none() && crate::kcp::first_push_packet(&raw) {
-            let new_session = Session::new(from, conv);
-            INCOMING.0.send(new_session).unwrap_or_default();
-            control = CONTROLS.get(key).and_then(|weak| weak.upgrade());
+            let new_session = Session::new(ekho.clone(), from, conv);
+            ekho.incoming().send(new_session).unwrap_or_default();
+            control = ekho.controls().get(key).and_then(|weak| weak.upgrade());
         }
         if let Some(control) = control {
-            // dissect_headers_from_raw(&raw, "recv");
-            let mut kcp = control.0.lock().await;
-            kcp.input(&raw).unwrap();
+            if let Some(capture) = ekho.capture() {
+                capture.write(from, Direction::Received, &raw);
+            }
+            let mut kcp = lock(&control).await;
+            if let Err(err) = kcp.input(&raw) {
+                debug!("dropping packet from {}: {}", from, err);
+                continue;
+            }
             control.1.notify_waiters();
+        } else if let Some(mut rst) = crate::kcp::reset_reply(&raw) {
+            // The session is already gone, so tell the peer to stop retransmitting
+            if ekho
+                .cipher()
+                .encrypt_in_place(&NONCE, b"", &mut rst)
+                .is_ok()
+            {
+                sender.send((from, rst)).await.unwrap();
+            }
         }
     }
 }
-
-pub async fn init_dispatch_loop() {
-    task::spawn(dispatch_loop());
-}
@@ -20,14 +20,21 @@ CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
 #![allow(dead_code)]
 
 use num_enum::{IntoPrimitive, TryFromPrimitive};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::convert::{TryFrom, TryInto};
 use std::fmt;
 use std::io::{Error, ErrorKind};
 use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
 use thiserror::Error;
-use tokio::net::TcpStream;
+use tokio::net::{lookup_host, TcpStream};
 
 pub const SOCKS5_VERSION: u8 = 0x05;
+pub const SOCKS5_AUTH_NONE: u8 = 0x00;
+pub const SOCKS5_AUTH_PASSWORD: u8 = 0x02;
+pub const SOCKS5_AUTH_UNACCEPTABLE: u8 = 0xff;
+/// Version of the username/password authentication sub-negotiation (RFC 1929).
+pub const SOCKS5_PASSWORD_VERSION: u8 = 0x01;
 const ATYP_IPV4: u8 = 0x01;
 const ATYP_DOMAIN_NAME: u8 = 0x03;
 const ATYP_IPV6: u8 = 0x04;
@@ -42,6 +49,8 @@ pub enum Socks5ParseError {
     InvalidCommand(u8),
     #[error("invalid error code: {0}")]
     InvalidErrorCode(u8),
+    #[error("invalid address: {0}")]
+    InvalidAddress(String),
 }
 
 type Result<T> = std::result::Result<T, Socks5ParseError>;
@@ -52,6 +61,12 @@ pub enum Socks5Command {
     Connect = 1,
     Bind = 2,
     UdpAssociate = 3,
+    /// Ekho extension (never sent by SOCKS5 clients): asks the server to keep listening on DST and
+    /// to relay every inbound connection back to the client.
+    RemoteForward = 0x80,
+    /// Ekho extension: turns the session into the IP tunnel between the TUN devices of the client
+    /// and the server, carrying every packet as a datagram.
+    Tun = 0x81,
 }
 
 #[derive(Debug, Clone, Copy, IntoPrimitive, TryFromPrimitive)]
@@ -81,7 +96,7 @@ impl From<Error> for Socks5Error {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Socks5Addr {
     Ip(IpAddr),
     Hostname(String),
@@ -92,22 +107,21 @@ impl Socks5Addr {
         if buf.is_empty() {
             return Err(Socks5ParseError::InvalidLength);
         }
-        let ret: Socks5Addr;
-        match buf[0] {
+        let ret = match buf[0] {
             ATYP_IPV4 if buf.len() == 5 => {
                 let octets: [u8; 4] = buf[1..].try_into().unwrap();
-                ret = Socks5Addr::Ip(IpAddr::from(octets));
+                Socks5Addr::Ip(IpAddr::from(octets))
             }
             ATYP_IPV6 if buf.len() == 17 => {
                 let octets: [u8; 16] = buf[1..].try_into().unwrap();
-                ret = Socks5Addr::Ip(IpAddr::from(octets));
+                Socks5Addr::Ip(IpAddr::from(octets))
             }
             ATYP_DOMAIN_NAME if buf.len() >= 2 && buf.len() == (2 + buf[1]) as usize => {
                 let domain = String::from_utf8_lossy(&buf[2..(2 + buf[1]) as usize]);
-                ret = Socks5Addr::Hostname(domain.parse().unwrap());
+                Socks5Addr::Hostname(domain.parse().unwrap())
             }
             _ => return Err(Socks5ParseError::InvalidLength),
-        }
+        };
         Ok(ret)
     }
 
@@ -145,7 +159,7 @@ impl fmt::Display for Socks5Addr {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Socks5SocketAddr {
     pub addr: Socks5Addr,
     pub port: u16,
@@ -176,6 +190,17 @@ impl Socks5SocketAddr {
             }
         }
     }
+
+    /// Resolves this address into a socket address, picking the first record for hostnames.
+    pub async fn resolve(&self) -> tokio::io::Result<SocketAddr> {
+        match &self.addr {
+            Socks5Addr::Ip(ip) => Ok(SocketAddr::new(*ip, self.port)),
+            Socks5Addr::Hostname(hostname) => lookup_host((hostname.as_str(), self.port))
+                .await?
+                .next()
+                .ok_or_else(|| Error::new(ErrorKind::NotFound, "no address found for host")),
+        }
+    }
 }
 
 impl From<SocketAddr> for Socks5SocketAddr {
@@ -187,6 +212,40 @@ impl From<SocketAddr> for Socks5SocketAddr {
     }
 }
 
+impl FromStr for Socks5SocketAddr {
+    type Err = Socks5ParseError;
+
+    /// Parses `host:port`, where host is an IPv4 address, a bracketed IPv6 address or a hostname.
+    fn from_str(s: &str) -> Result<Self> {
+        if let Ok(socket_addr) = s.parse::<SocketAddr>() {
+            return Ok(socket_addr.into());
+        }
+        let invalid = || Socks5ParseError::InvalidAddress(s.into());
+        let (host, port) = s.rsplit_once(':').ok_or_else(invalid)?;
+        if host.is_empty() || host.len() > u8::MAX as usize || host.contains(':') {
+            return Err(invalid());
+        }
+        Ok(Socks5SocketAddr {
+            addr: Socks5Addr::Hostname(host.into()),
+            port: port.parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+impl Serialize for Socks5SocketAddr {
+    fn serialize<S: Serializer>(&self, s: S) -> std::result::Result<S::Ok, S::Error> {
+        s.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Socks5SocketAddr {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> std::result::Result<Self, D::Error> {
+        String::deserialize(d)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
 impl fmt::Display for Socks5SocketAddr {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "{}:{}", self.addr, self.port)
@@ -261,6 +320,35 @@ impl Socks5Reply {
     }
 }
 
+/// Username/password authentication request (RFC 1929).
+pub struct Socks5PasswordAuth {
+    pub username: String,
+    pub password: String,
+}
+
+impl Socks5PasswordAuth {
+    pub fn parse(buf: &[u8]) -> Result<Self> {
+        if buf.len() < 2 {
+            return Err(Socks5ParseError::InvalidLength);
+        }
+        if buf[0] != SOCKS5_PASSWORD_VERSION {
+            return Err(Socks5ParseError::InvalidProtocol(buf[0]));
+        }
+        let username_len = buf[1] as usize;
+        if buf.len() < 3 + username_len {
+            return Err(Socks5ParseError::InvalidLength);
+        }
+        let password_len = buf[2 + username_len] as usize;
+        if buf.len() != 3 + username_len + password_len {
+            return Err(Socks5ParseError::InvalidLength);
+        }
+        Ok(Socks5PasswordAuth {
+            username: String::from_utf8_lossy(&buf[2..2 + username_len]).into(),
+            password: String::from_utf8_lossy(&buf[3 + username_len..]).into(),
+        })
+    }
+}
+
 pub struct Socks5UdpEncapsulation {
     pub frag: u8,
     pub dst: Socks5SocketAddr,
@@ -273,12 +361,16 @@ impl Socks5UdpEncapsulation {
             return Err(Socks5ParseError::InvalidLength);
         }
         let frag = buf[2];
+        // Address (including ATYP) followed by a 2-byte port
         let addr_len = match buf[3] {
-            ATYP_IPV4 => 5,
-            ATYP_IPV6 => 17,
-            ATYP_DOMAIN_NAME => buf[4] as usize + 2,
+            ATYP_IPV4 => 5 + 2,
+            ATYP_IPV6 => 17 + 2,
+            ATYP_DOMAIN_NAME => buf[4] as usize + 2 + 2,
             _ => return Err(Socks5ParseError::InvalidLength),
         };
+        if buf.len() < 3 + addr_len {
+            return Err(Socks5ParseError::InvalidLength);
+        }
         let dst = Socks5SocketAddr::parse(&buf[3..3 + addr_len])?;
         let data = Vec::from(&buf[3 + addr_len..]);
         Ok(Socks5UdpEncapsulation { frag, dst, data })
@@ -291,3 +383,119 @@ impl Socks5UdpEncapsulation {
         ret
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn password_auth(buf: &[u8]) -> Result<(String, String)> {
+        Socks5PasswordAuth::parse(buf).map(|auth| (auth.username, auth.password))
+    }
+
+    #[test]
+    fn password_auth_parse() {
+        assert_eq!(
+            password_auth(b"\x01\x04user\x06secret").unwrap(),
+            ("user".into(), "secret".into())
+        );
+        assert_eq!(
+            password_auth(b"\x01\x00\x00").unwrap(),
+            (String::new(), String::new())
+        );
+        assert_eq!(
+            password_auth(b"\x01\x00\x06secret").unwrap(),
+            (String::new(), "secret".into())
+        );
+        assert_eq!(
+            password_auth(b"\x01\x04user\x00").unwrap(),
+            ("user".into(), String::new())
+        );
+    }
+
+    #[test]
+    fn password_auth_malformed() {
+        assert!(matches!(
+            password_auth(b"\x05\x04user\x06secret"),
+            Err(Socks5ParseError::InvalidProtocol(0x05))
+        ));
+        for buf in [
+            &b""[..],
+            b"\x01",
+            b"\x01\x00",
+            b"\x01\x04use",
+            b"\x01\x04user",
+            b"\x01\x04user\x06secre",
+            b"\x01\x04user\x06secrets",
+            b"\x01\x00\x00\x00",
+        ] {
+            assert!(
+                matches!(password_auth(buf), Err(Socks5ParseError::InvalidLength)),
+                "{:?}",
+                buf
+            );
+        }
+    }
+
+    #[test]
+    fn udp_encapsulation_parse() {
+        let datagram = Socks5UdpEncapsulation::parse(b"\0\0\0\x01\x7f\0\0\x01\0\x35data").unwrap();
+        assert_eq!(datagram.frag, 0);
+        assert_eq!(
+            datagram.dst,
+            "127.0.0.1:53".parse::<SocketAddr>().unwrap().into()
+        );
+        assert_eq!(datagram.data, b"data");
+        assert_eq!(
+            Socks5UdpEncapsulation::parse(&datagram.marshal())
+                .unwrap()
+                .data,
+            b"data"
+        );
+
+        let datagram =
+            Socks5UdpEncapsulation::parse(b"\0\0\0\x03\x0bexample.com\x01\xbbdata").unwrap();
+        assert_eq!(
+            datagram.dst,
+            Socks5SocketAddr {
+                addr: Socks5Addr::Hostname("example.com".into()),
+                port: 443,
+            }
+        );
+        assert_eq!(datagram.data, b"data");
+
+        // Without any data
+        let datagram = Socks5UdpEncapsulation::parse(b"\0\0\0\x03\x0bexample.com\x01\xbb").unwrap();
+        assert!(datagram.data.is_empty());
+    }
+
+    #[test]
+    fn udp_encapsulation_fragment() {
+        // Fragments are parsed, and then dropped by the relay
+        let datagram =
+            Socks5UdpEncapsulation::parse(b"\0\0\x02\x01\x7f\0\0\x01\0\x35data").unwrap();
+        assert_eq!(datagram.frag, 2);
+        assert_eq!(datagram.data, b"data");
+    }
+
+    #[test]
+    fn udp_encapsulation_truncated() {
+        for buf in [
+            &b""[..],
+            b"\0\0\0\x01",
+            b"\0\0\0\x01\x7f\0\0\x01\0",
+            b"\0\0\0\x04\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\x01\0",
+            b"\0\0\0\x03\x0bexample.com\x01",
+            b"\0\0\0\x03\x0bexample",
+            b"\0\0\0\x09\x7f\0\0\x01\0\x35",
+        ] {
+            assert!(
+                matches!(
+                    Socks5UdpEncapsulation::parse(buf),
+                    Err(Socks5ParseError::InvalidLength)
+                ),
+                "{:?}",
+                buf
+            );
+        }
+    }
+}
@@ -0,0 +1,98 @@
+/*
+Copyright 2021 Chengyuan Ma
+
+Permission is hereby granted, free of charge, to any person obtaining a copy of this software and
+associated documentation files (the "Software"), to deal in the Software without restriction,
+including without limitation the rights to use, copy, modify, merge, publish, distribute, sub-
+-license, and/or sell copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all copies or substantial
+portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING BUT
+NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NON-
+-INFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES
+OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+*/
+
+//! Transparent proxy support (Linux only, TCP only).
+//!
+//! A `redirect` listener takes connections diverted by the netfilter `REDIRECT` (or `DNAT`) target
+//! and recovers their original destination with `SO_ORIGINAL_DST`:
+//!
+//! ```sh
+//! iptables -t nat -A PREROUTING -i br0 -p tcp -j REDIRECT --to-ports 12345
+//! ```
+//!
+//! A `tproxy` listener takes connections diverted by the `TPROXY` target. The original destination
+//! is then simply the local address of the accepted socket, but the listening socket needs
+//! `IP_TRANSPARENT`, which in turn needs `CAP_NET_ADMIN`:
+//!
+//! ```sh
+//! iptables -t mangle -A PREROUTING -i br0 -p tcp -j TPROXY --on-port 12345 --tproxy-mark 1
+//! ip rule add fwmark 1 lookup 100
+//! ip route add local 0.0.0.0/0 dev lo table 100
+//! ```
+//!
+//! UDP is not diverted this way: use [TUN mode](crate::tun) to route whole hosts through the tunnel,
+//! or a `socks5` listener for UDP ASSOCIATE.
+
+pub use platform_impl::{original_dst, set_transparent};
+
+#[cfg(target_os = "linux")]
+mod platform_impl {
+    use socket2::{SockRef, Socket};
+    use std::io;
+    use std::net::{IpAddr, SocketAddr};
+    use tokio::net::TcpStream;
+
+    /// Sets `IP_TRANSPARENT` (and `IPV6_TRANSPARENT` for IPv6 sockets) on a listening socket.
+    pub fn set_transparent(socket: &Socket, ipv6: bool) -> io::Result<()> {
+        socket.set_ip_transparent_v4(true)?;
+        if ipv6 {
+            socket.set_ip_transparent_v6(true)?;
+        }
+        Ok(())
+    }
+
+    /// Recovers the destination of a connection before it was redirected to us.
+    pub fn original_dst(stream: &TcpStream) -> io::Result<SocketAddr> {
+        let socket = SockRef::from(stream);
+        // IPv4 connections accepted by a dual-stack listener still go through the IPv4 netfilter
+        let addr = match stream.local_addr()?.ip() {
+            IpAddr::V6(ip) if ip.to_ipv4_mapped().is_none() => socket.original_dst_v6()?,
+            _ => socket.original_dst_v4()?,
+        };
+        addr.as_socket().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "original destination is not an IP address",
+            )
+        })
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod platform_impl {
+    use socket2::Socket;
+    use std::io;
+    use std::net::SocketAddr;
+    use tokio::net::TcpStream;
+
+    fn unsupported() -> io::Error {
+        io::Error::new(
+            io::ErrorKind::Other,
+            "transparent proxy is only supported on Linux",
+        )
+    }
+
+    pub fn set_transparent(_socket: &Socket, _ipv6: bool) -> io::Result<()> {
+        Err(unsupported())
+    }
+
+    pub fn original_dst(_stream: &TcpStream) -> io::Result<SocketAddr> {
+        Err(unsupported())
+    }
+}
@@ -0,0 +1,340 @@
+/*
+Copyright 2021 Chengyuan Ma
+
+Permission is hereby granted, free of charge, to any person obtaining a copy of this software and
+associated documentation files (the "Software"), to deal in the Software without restriction,
+including without limitation the rights to use, copy, modify, merge, publish, distribute, sub-
+-license, and/or sell copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all copies or substantial
+portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING BUT
+NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NON-
+-INFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES
+OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+*/
+
+//! Point-to-point VPN over a TUN device (Linux only).
+//!
+//! When `[tun]` is configured, the client and the server each open a TUN interface. The client
+//! then keeps a session to the server open with a [Tun](Socks5Command::Tun) request, and raw IP
+//! packets are carried over it as unreliable datagrams. Retransmission is thus left to the
+//! protocols inside the tunnel, which avoids TCP-in-TCP meltdown.
+//!
+//! ```toml
+//! [tun]
+//! name = "ekho0"
+//! address = "10.8.0.2/24"
+//! routes = ["192.168.10.0/24"]
+//! ```
+//!
+//! The server tunnels to one client at a time: a new tunnel session replaces the previous one. The
+//! interface is configured with `ip(8)`, which needs `CAP_NET_ADMIN`.
+
+use crate::ekho::Ekho;
+use crate::route::Cidr;
+use crate::session::Session;
+use crate::socks5::{Socks5Command, Socks5Error, Socks5Reply, Socks5Request};
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use tokio::process::Command;
+use tokio::select;
+use tokio::sync::{Mutex, Notify};
+use tokio::time::{sleep, Duration};
+use tracing::{error, info, instrument, warn};
+
+pub use platform_impl::Device;
+
+/// Delay before reopening the tunnel session after it fails or the server drops it.
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// An open TUN device, along with the state of the tunnel session using it.
+pub struct Tunnel {
+    device: Device,
+    address: Cidr,
+    /// Held by the session currently tunneling packets on the server.
+    active: Mutex<()>,
+    /// Tells the active tunnel session on the server to give way to a new one.
+    takeover: Notify,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Config {
+    #[serde(default = "default_name")]
+    pub name: String,
+    /// The local address, with the prefix length of the tunnel network.
+    pub address: Cidr,
+    /// Networks routed into the tunnel, besides the tunnel network itself.
+    #[serde(default)]
+    pub routes: Vec<Cidr>,
+    /// Defaults to the largest packet that fits in a KCP segment.
+    #[serde(default)]
+    pub mtu: Option<u32>,
+}
+
+fn default_name() -> String {
+    String::from("ekho0")
+}
+
+/// The largest IP packet that can be carried in a single datagram.
+pub fn max_mtu(kcp: &crate::kcp::Config) -> u32 {
+    kcp.mss() as u32
+}
+
+async fn ip(args: &[&str]) -> Result<()> {
+    let status = Command::new("ip")
+        .args(args)
+        .status()
+        .await
+        .with_context(|| format!("running `ip {}`", args.join(" ")))?;
+    if !status.success() {
+        bail!("`ip {}` failed with {}", args.join(" "), status);
+    }
+    Ok(())
+}
+
+impl Config {
+    /// Creates the TUN interface, then assigns its address, MTU and routes.
+    pub async fn open(&self, kcp: &crate::kcp::Config) -> Result<Tunnel> {
+        let mtu = self.mtu.unwrap_or_else(|| max_mtu(kcp));
+        if mtu > max_mtu(kcp) {
+            bail!(
+                "TUN MTU {} is larger than {} allowed by the KCP MTU",
+                mtu,
+                max_mtu(kcp)
+            );
+        }
+        let device = Device::open(&self.name, mtu as usize)
+            .with_context(|| format!("opening TUN device {}", self.name))?;
+        ip(&[
+            "address",
+            "add",
+            &self.address.to_string(),
+            "dev",
+            &self.name,
+        ])
+        .await?;
+        ip(&[
+            "link",
+            "set",
+            "dev",
+            &self.name,
+            "mtu",
+            &mtu.to_string(),
+            "up",
+        ])
+        .await?;
+        for route in &self.routes {
+            ip(&["route", "add", &route.to_string(), "dev", &self.name]).await?;
+        }
+        info!(
+            "TUN device {} up with {}, mtu {}",
+            self.name, self.address, mtu
+        );
+        Ok(Tunnel {
+            device,
+            address: self.address,
+            active: Mutex::new(()),
+            takeover: Notify::new(),
+        })
+    }
+}
+
+/// Carries packets between the TUN device and `session` until the session ends.
+async fn pump(device: &Device, session: &Session) {
+    let outgoing = async {
+        let mut buf = vec![0; device.mtu()];
+        loop {
+            match device.recv(&mut buf).await {
+                Ok(len) => session.send_datagram(&buf[..len]).await,
+                Err(err) => {
+                    error!("error reading from TUN device: {}", err);
+                    return;
+                }
+            }
+        }
+    };
+    let incoming = async {
+        loop {
+            let packet = session.recv_datagram().await;
+            if let Err(err) = device.send(&packet).await {
+                error!("error writing to TUN device: {}", err);
+            }
+        }
+    };
+    // Nothing but the end of the stream is expected on the reliable side of the session.
+    select! {
+        _ = outgoing => {}
+        _ = incoming => {}
+        _ = session.recv() => {}
+    }
+}
+
+/// Keeps the tunnel session to the server open on the client. Does nothing without a TUN device.
+#[instrument]
+pub async fn connect(ekho: Ekho) {
+    let tunnel = match ekho.tun() {
+        Some(tunnel) => tunnel,
+        None => return,
+    };
+    let request = Socks5Request {
+        cmd: Socks5Command::Tun,
+        dst: SocketAddr::new(tunnel.address.addr(), 0).into(),
+    };
+    loop {
+        let session = ekho.connect(ekho.config().remote.unwrap());
+        session.send(&request.marshal()).await;
+        match Socks5Reply::parse(&session.recv().await) {
+            Ok(Socks5Reply::Success { .. }) => {
+                info!("IP tunnel established");
+                pump(&tunnel.device, &session).await;
+                warn!("IP tunnel dropped by server");
+            }
+            Ok(Socks5Reply::Error(err)) => error!("server refused IP tunnel: {:?}", err),
+            Err(err) => error!("invalid reply for IP tunnel: {}", err),
+        }
+        session.close().await;
+        sleep(RETRY_INTERVAL).await;
+    }
+}
+
+/// Serves a tunnel session on the server, taking over from the previous one if any.
+pub async fn serve(session: Session) -> Result<()> {
+    let ekho = session.ekho().clone();
+    let tunnel = match ekho.tun() {
+        Some(tunnel) => tunnel,
+        None => {
+            warn!("IP tunnel requested but no TUN device is configured");
+            session
+                .send(&Socks5Reply::Error(Socks5Error::CommandNotSupported).marshal())
+                .await;
+            session.close().await;
+            return Ok(());
+        }
+    };
+    tunnel.takeover.notify_waiters();
+    let guard = tunnel.active.lock().await;
+    session
+        .send(
+            &Socks5Reply::Success {
+                bnd: SocketAddr::new(tunnel.address.addr(), 0).into(),
+            }
+            .marshal(),
+        )
+        .await;
+    info!("IP tunnel established with {}", session.peer());
+    select! {
+        _ = pump(&tunnel.device, &session) => {}
+        _ = tunnel.takeover.notified() => info!("IP tunnel taken over by a new session"),
+    }
+    drop(guard);
+    session.close().await;
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+mod platform_impl {
+    use std::fs::{File, OpenOptions};
+    use std::io::{self, Read, Write};
+    use std::os::unix::fs::OpenOptionsExt;
+    use std::os::unix::io::AsRawFd;
+    use tokio::io::unix::AsyncFd;
+
+    /// `struct ifreq` with the `ifr_flags` member of its union.
+    #[repr(C)]
+    struct IfReq {
+        name: [u8; libc::IFNAMSIZ],
+        flags: libc::c_short,
+        _pad: [u8; 22],
+    }
+
+    pub struct Device {
+        fd: AsyncFd<File>,
+        mtu: usize,
+    }
+
+    impl Device {
+        pub fn open(name: &str, mtu: usize) -> io::Result<Self> {
+            if name.len() >= libc::IFNAMSIZ {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "interface name too long",
+                ));
+            }
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .custom_flags(libc::O_NONBLOCK)
+                .open("/dev/net/tun")?;
+            let mut req = IfReq {
+                name: [0; libc::IFNAMSIZ],
+                flags: (libc::IFF_TUN | libc::IFF_NO_PI) as libc::c_short,
+                _pad: [0; 22],
+            };
+            req.name[..name.len()].copy_from_slice(name.as_bytes());
+            if unsafe { libc::ioctl(file.as_raw_fd(), libc::TUNSETIFF, &mut req) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            // The file is owned by the AsyncFd from here on, so its descriptor stays valid
+            let fd = unsafe { AsyncFd::register(file)? };
+            Ok(Device { fd, mtu })
+        }
+
+        pub fn mtu(&self) -> usize {
+            self.mtu
+        }
+
+        /// Reads one IP packet.
+        pub async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+            loop {
+                let mut guard = self.fd.readable().await?;
+                if let Ok(res) = guard.try_io(|fd| fd.get_ref().read(buf)) {
+                    return res;
+                }
+            }
+        }
+
+        /// Writes one IP packet.
+        pub async fn send(&self, packet: &[u8]) -> io::Result<()> {
+            loop {
+                let mut guard = self.fd.writable().await?;
+                if let Ok(res) = guard.try_io(|fd| fd.get_ref().write(packet)) {
+                    return res.map(|_| ());
+                }
+            }
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod platform_impl {
+    use std::io;
+
+    pub struct Device;
+
+    fn unsupported() -> io::Error {
+        io::Error::new(io::ErrorKind::Other, "TUN mode is only supported on Linux")
+    }
+
+    impl Device {
+        pub fn open(_name: &str, _mtu: usize) -> io::Result<Self> {
+            Err(unsupported())
+        }
+
+        pub fn mtu(&self) -> usize {
+            0
+        }
+
+        pub async fn recv(&self, _buf: &mut [u8]) -> io::Result<usize> {
+            Err(unsupported())
+        }
+
+        pub async fn send(&self, _packet: &[u8]) -> io::Result<()> {
+            Err(unsupported())
+        }
+    }
+}
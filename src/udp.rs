@@ -0,0 +1,280 @@
+/*
+Copyright 2021 Chengyuan Ma
+
+Permission is hereby granted, free of charge, to any person obtaining a copy of this software and
+associated documentation files (the "Software"), to deal in the Software without restriction,
+including without limitation the rights to use, copy, modify, merge, publish, distribute, sub-
+-license, and/or sell copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all copies or substantial
+portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING BUT
+NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NON-
+-INFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES
+OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+*/
+
+//! UDP relay for the SOCKS5 UDP ASSOCIATE command.
+//!
+//! Each association gets a dedicated session. Every UDP datagram is prefixed with the SOCKS5 UDP
+//! request header (see [Socks5UdpEncapsulation]) so that the server knows where to send it and the
+//! client knows where it came from, then sent as a session datagram. Those too large for a single
+//! KCP segment are sent as reliable messages instead.
+//!
+//! The client routes each datagram by its destination like a CONNECT request: datagrams routed
+//! `direct` are sent from the client itself, and those routed `reject` are dropped.
+
+use crate::route::Action;
+use crate::session::Session;
+use crate::socks5::Socks5UdpEncapsulation;
+use anyhow::Result;
+use derivative::Derivative;
+use serde::{Deserialize, Serialize};
+use std::future::pending;
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::AsyncReadExt;
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::select;
+use tokio::time::{sleep, Duration};
+use tracing::debug;
+
+/// UDP relay configuration.
+#[derive(Clone, Debug, Deserialize, Serialize, Derivative)]
+#[derivative(Default)]
+#[serde(default)]
+pub struct Config {
+    /// An association without any traffic for this long (unit: s) is torn down by the server. 0
+    /// means associations never time out.
+    #[derivative(Default(value = "60"))]
+    pub idle_timeout: u64,
+}
+
+/// Large enough for any UDP datagram.
+const MAX_DATAGRAM: usize = 65536;
+
+/// Sends an encapsulated UDP datagram as a session datagram if it fits, or reliably otherwise.
+async fn send_packet(session: &Session, packet: &[u8]) {
+    if packet.len() <= session.ekho().config().kcp.mss() {
+        session.send_datagram(packet).await;
+    } else {
+        session.send(packet).await;
+    }
+}
+
+async fn forward_udp_local(
+    control: &mut TcpStream,
+    socket: &UdpSocket,
+    session: &Session,
+) -> Result<()> {
+    let client_ip = control.peer_addr()?.ip();
+    let max_payload = session.ekho().config().kcp.max_payload();
+    // Datagrams routed directly go out of these sockets, and their replies come back on them
+    let direct_v4 = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    let direct_v6 = UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0)).await.ok();
+    let mut client = None;
+    let mut buf = vec![0; MAX_DATAGRAM];
+    let mut buf_v4 = vec![0; MAX_DATAGRAM];
+    let mut buf_v6 = vec![0; MAX_DATAGRAM];
+    let mut control_buf = [0; 64];
+    loop {
+        select! {
+            // The association terminates when the TCP connection it arrived on terminates.
+            res = control.read(&mut control_buf) => match res {
+                Ok(0) | Err(_) => break,
+                Ok(_) => continue,
+            },
+            res = socket.recv_from(&mut buf) => {
+                let (len, from) = res?;
+                // Only accept datagrams from the host that requested the association, and stick to
+                // the first port we see from it.
+                if from.ip() != client_ip || matches!(client, Some(client) if client != from) {
+                    continue;
+                }
+                client = Some(from);
+                let datagram = match Socks5UdpEncapsulation::parse(&buf[..len]) {
+                    Ok(datagram) if datagram.frag == 0 => datagram,
+                    _ => {
+                        debug!("dropping datagram from {}", from);
+                        continue;
+                    }
+                };
+                match session.ekho().config().routing.route(&datagram.dst) {
+                    Action::Proxy if len <= max_payload => send_packet(session, &buf[..len]).await,
+                    Action::Proxy => debug!("dropping datagram too large for the tunnel"),
+                    Action::Direct => {
+                        let dst = datagram.dst.clone();
+                        if let Err(err) = send_datagram(&direct_v4, &direct_v6, datagram).await {
+                            debug!("error sending datagram to {}: {}", dst, err);
+                        }
+                    }
+                    Action::Reject => debug!("rejecting datagram to {}", datagram.dst),
+                }
+            }
+            res = direct_v4.recv_from(&mut buf_v4) => {
+                let (len, from) = res?;
+                reply_direct(socket, client, from, &buf_v4[..len]).await?;
+            }
+            res = recv_from_optional(&direct_v6, &mut buf_v6) => {
+                let (len, from) = res?;
+                reply_direct(socket, client, from, &buf_v6[..len]).await?;
+            }
+            data = session.recv() => {
+                if data.is_empty() {
+                    break;
+                }
+                if let Some(client) = client {
+                    socket.send_to(&data, client).await?;
+                }
+            }
+            data = session.recv_datagram() => {
+                if let Some(client) = client {
+                    socket.send_to(&data, client).await?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Hands a datagram received from `from` on a direct socket to the local client, if known yet.
+async fn reply_direct(
+    socket: &UdpSocket,
+    client: Option<SocketAddr>,
+    from: SocketAddr,
+    data: &[u8],
+) -> io::Result<()> {
+    if let Some(client) = client {
+        let packet = Socks5UdpEncapsulation {
+            frag: 0,
+            dst: from.into(),
+            data: data.into(),
+        }
+        .marshal();
+        socket.send_to(&packet, client).await?;
+    }
+    Ok(())
+}
+
+/// Relays datagrams between a local SOCKS5 client and the server or, for those routed directly,
+/// their destinations, until either the client closes the TCP connection that requested the
+/// association or the server tears the association down.
+pub async fn relay_udp_local(
+    mut control: TcpStream,
+    socket: UdpSocket,
+    session: Session,
+) -> Result<()> {
+    let res = forward_udp_local(&mut control, &socket, &session).await;
+    session.close().await;
+    res
+}
+
+async fn recv_from_optional(
+    socket: &Option<UdpSocket>,
+    buf: &mut [u8],
+) -> io::Result<(usize, SocketAddr)> {
+    match socket {
+        Some(socket) => socket.recv_from(buf).await,
+        None => pending().await,
+    }
+}
+
+async fn send_datagram(
+    socket_v4: &UdpSocket,
+    socket_v6: &Option<UdpSocket>,
+    datagram: Socks5UdpEncapsulation,
+) -> io::Result<()> {
+    let dst = datagram.dst.resolve().await?;
+    match (dst, socket_v6) {
+        (SocketAddr::V4(_), _) => socket_v4.send_to(&datagram.data, dst).await?,
+        (SocketAddr::V6(_), Some(socket_v6)) => socket_v6.send_to(&datagram.data, dst).await?,
+        (SocketAddr::V6(_), None) => {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                "IPv6 is not available",
+            ))
+        }
+    };
+    Ok(())
+}
+
+/// Sends an encapsulated datagram from the client to its destination.
+async fn forward_to_remote(socket_v4: &UdpSocket, socket_v6: &Option<UdpSocket>, packet: &[u8]) {
+    match Socks5UdpEncapsulation::parse(packet) {
+        Ok(datagram) if datagram.frag == 0 => {
+            let dst = datagram.dst.clone();
+            if let Err(err) = send_datagram(socket_v4, socket_v6, datagram).await {
+                debug!("error sending datagram to {}: {}", dst, err);
+            }
+        }
+        _ => debug!("dropping malformed datagram"),
+    }
+}
+
+/// Returns after `timeout`, or never without one.
+async fn idle(timeout: Option<Duration>) {
+    match timeout {
+        Some(timeout) => sleep(timeout).await,
+        None => pending().await,
+    }
+}
+
+async fn forward_udp_remote(socket_v4: &UdpSocket, session: &Session) -> Result<()> {
+    let socket_v6 = UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0)).await.ok();
+    let config = session.ekho().config();
+    let idle_timeout = match config.udp.idle_timeout {
+        0 => None,
+        secs => Some(Duration::from_secs(secs)),
+    };
+    let max_payload = config.kcp.max_payload();
+    let mut buf_v4 = vec![0; MAX_DATAGRAM];
+    let mut buf_v6 = vec![0; MAX_DATAGRAM];
+    loop {
+        let (len, from, buf) = select! {
+            _ = idle(idle_timeout) => {
+                debug!("UDP association idle for {}s", config.udp.idle_timeout);
+                break;
+            }
+            data = session.recv() => {
+                if data.is_empty() {
+                    break;
+                }
+                forward_to_remote(socket_v4, &socket_v6, &data).await;
+                continue;
+            }
+            data = session.recv_datagram() => {
+                forward_to_remote(socket_v4, &socket_v6, &data).await;
+                continue;
+            }
+            res = socket_v4.recv_from(&mut buf_v4) => {
+                let (len, from) = res?;
+                (len, from, &buf_v4)
+            }
+            res = recv_from_optional(&socket_v6, &mut buf_v6) => {
+                let (len, from) = res?;
+                (len, from, &buf_v6)
+            }
+        };
+        let packet = Socks5UdpEncapsulation {
+            frag: 0,
+            dst: from.into(),
+            data: buf[..len].into(),
+        }
+        .marshal();
+        if packet.len() <= max_payload {
+            send_packet(session, &packet).await;
+        }
+    }
+    Ok(())
+}
+
+/// Relays datagrams between the client and the rest of the Internet on the server side, until the
+/// client ends the association or it has been idle for too long.
+pub async fn relay_udp_remote(socket: UdpSocket, session: Session) -> Result<()> {
+    let res = forward_udp_remote(&socket, &session).await;
+    session.close().await;
+    res
+}
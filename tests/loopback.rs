@@ -0,0 +1,294 @@
+/*
+Copyright 2021 Chengyuan Ma
+
+Permission is hereby granted, free of charge, to any person obtaining a copy of this software and
+associated documentation files (the "Software"), to deal in the Software without restriction,
+including without limitation the rights to use, copy, modify, merge, publish, distribute, sub-
+-license, and/or sell copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all copies or substantial
+portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING BUT
+NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NON-
+-INFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES
+OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+*/
+
+//! A client and a server running in the same process, linked by a loopback transport.
+
+use ekho::client::connect;
+use ekho::config::{generate_key, Config};
+use ekho::ekho::Ekho;
+use ekho::icmp::{Endpoint, Transport};
+use ekho::session::Session;
+use ekho::socks5::{Socks5Addr, Socks5Command, Socks5Reply, Socks5Request, Socks5SocketAddr};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+use tokio::task;
+use tokio::time::{sleep, timeout, Duration};
+
+const CLIENT_IP: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 1);
+const SERVER_IP: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 2);
+const ID: u16 = 1234;
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A bound client and server sharing a key.
+async fn instances() -> (Ekho, Ekho) {
+    let key = hex::encode(generate_key());
+    let config = |remote: &str| -> Config {
+        toml::from_str(&format!("{}key = \"{}\"\n[kcp]\n[icmp]\n", remote, key)).unwrap()
+    };
+    let client_config = config(&format!(
+        "remote = {{ ip = \"{}\", id = {} }}\n",
+        SERVER_IP, ID
+    ));
+    let server_config = config("");
+    let (client_transport, server_transport) = Transport::pair(CLIENT_IP, SERVER_IP, 1024);
+    let client = Ekho::with_transport(client_config, client_transport)
+        .await
+        .unwrap();
+    let server = Ekho::with_transport(server_config, server_transport)
+        .await
+        .unwrap();
+    (client, server)
+}
+
+#[tokio::test]
+async fn session_round_trip() {
+    let (client, server) = instances().await;
+    let echo = task::spawn(async move {
+        let session = server.accept().await;
+        assert_eq!(
+            session.peer(),
+            Endpoint {
+                ip: CLIENT_IP,
+                id: ID
+            }
+        );
+        loop {
+            let message = session.recv().await;
+            if message.is_empty() {
+                break;
+            }
+            session.send(&message).await;
+        }
+        session.close().await;
+    });
+
+    let session = client.connect(client.config().remote.unwrap());
+    for message in [&b"hello"[..], &[0x42; 4000], b"bye"] {
+        session.send(message).await;
+        let reply = timeout(TIMEOUT, session.recv()).await.unwrap();
+        assert_eq!(reply, message);
+    }
+    session.shutdown().await;
+    timeout(TIMEOUT, echo).await.unwrap().unwrap();
+    assert!(timeout(TIMEOUT, session.recv()).await.unwrap().is_empty());
+    session.close().await;
+}
+
+#[tokio::test]
+async fn connect_through_server() {
+    let (client, server) = instances().await;
+    task::spawn(ekho::server::run(server));
+
+    // The destination, echoing back whatever it receives
+    let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target_addr = target.local_addr().unwrap();
+    task::spawn(async move {
+        let (mut stream, _) = target.accept().await.unwrap();
+        let (mut reader, mut writer) = stream.split();
+        tokio::io::copy(&mut reader, &mut writer).await.unwrap();
+    });
+
+    let (reply, outbound) = timeout(TIMEOUT, connect(&client, &target_addr.into()))
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(matches!(reply, Socks5Reply::Success { .. }));
+
+    // The application, whose connection is relayed to the destination through the server
+    let local = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let mut app = TcpStream::connect(local.local_addr().unwrap())
+        .await
+        .unwrap();
+    let (accepted, _) = local.accept().await.unwrap();
+    let relay_client = client.clone();
+    let relay = task::spawn(async move {
+        outbound
+            .unwrap()
+            .relay(&relay_client, accepted)
+            .await
+            .unwrap()
+    });
+
+    let data: Vec<u8> = (0..100_000u32).map(|i| i as u8).collect();
+    app.write_all(&data).await.unwrap();
+    let mut echoed = vec![0; data.len()];
+    timeout(TIMEOUT, app.read_exact(&mut echoed))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(echoed, data);
+
+    app.shutdown().await.unwrap();
+    let mut rest = Vec::new();
+    timeout(TIMEOUT, app.read_to_end(&mut rest))
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(rest.is_empty());
+    timeout(TIMEOUT, relay).await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn half_close_through_server() {
+    let (client, server) = instances().await;
+    task::spawn(ekho::server::run(server));
+
+    // The destination, answering only once the application has finished sending
+    let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target_addr = target.local_addr().unwrap();
+    let target = task::spawn(async move {
+        let (mut stream, _) = target.accept().await.unwrap();
+        let mut request = Vec::new();
+        stream.read_to_end(&mut request).await.unwrap();
+        for _ in 0..3 {
+            sleep(Duration::from_millis(100)).await;
+            stream.write_all(&request).await.unwrap();
+        }
+        stream.shutdown().await.unwrap();
+        request
+    });
+
+    let (_, outbound) = timeout(TIMEOUT, connect(&client, &target_addr.into()))
+        .await
+        .unwrap()
+        .unwrap();
+    let local = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let mut app = TcpStream::connect(local.local_addr().unwrap())
+        .await
+        .unwrap();
+    let (accepted, _) = local.accept().await.unwrap();
+    let relay_client = client.clone();
+    let relay = task::spawn(async move {
+        outbound
+            .unwrap()
+            .relay(&relay_client, accepted)
+            .await
+            .unwrap()
+    });
+
+    app.write_all(b"request").await.unwrap();
+    app.shutdown().await.unwrap();
+    assert_eq!(timeout(TIMEOUT, target).await.unwrap().unwrap(), b"request");
+    let mut response = Vec::new();
+    timeout(TIMEOUT, app.read_to_end(&mut response))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(response, b"request".repeat(3));
+    timeout(TIMEOUT, relay).await.unwrap().unwrap();
+}
+
+/// Reads a reply to a SOCKS5 request from the server, which must be a success.
+async fn success(session: &Session) -> Socks5SocketAddr {
+    let reply = timeout(TIMEOUT, session.recv()).await.unwrap();
+    match Socks5Reply::parse(&reply).unwrap() {
+        Socks5Reply::Success { bnd } => bnd,
+        reply => panic!("unexpected reply {:?}", reply),
+    }
+}
+
+/// Sends a BIND request for `dst` to the server, returning the session and the address the
+/// server listens on.
+async fn bind(client: &Ekho, dst: &str) -> (Session, Socks5SocketAddr) {
+    let session = client.connect(client.config().remote.unwrap());
+    let request = Socks5Request {
+        cmd: Socks5Command::Bind,
+        dst: dst.parse().unwrap(),
+    };
+    session.send(&request.marshal()).await;
+    let bnd = success(&session).await;
+    (session, bnd)
+}
+
+/// Connects to the port of `bnd` on 127.0.0.1 from `from`.
+async fn connect_from(from: Ipv4Addr, bnd: &Socks5SocketAddr) -> TcpStream {
+    let socket = TcpSocket::new_v4().unwrap();
+    socket.bind((from, 0).into()).unwrap();
+    socket
+        .connect((Ipv4Addr::LOCALHOST, bnd.port).into())
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn bind_through_server() {
+    let (client, server) = instances().await;
+    task::spawn(ekho::server::run(server));
+
+    let (session, bnd) = bind(&client, "127.0.0.1:0").await;
+    assert_eq!(bnd.addr, Socks5Addr::Ip(Ipv4Addr::LOCALHOST.into()));
+    let mut remote = connect_from(Ipv4Addr::LOCALHOST, &bnd).await;
+    assert_eq!(success(&session).await, remote.local_addr().unwrap().into());
+
+    session.send(b"ping").await;
+    let mut buf = [0; 4];
+    timeout(TIMEOUT, remote.read_exact(&mut buf))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(&buf, b"ping");
+    remote.write_all(b"pong").await.unwrap();
+    assert_eq!(timeout(TIMEOUT, session.recv()).await.unwrap(), b"pong");
+
+    remote.shutdown().await.unwrap();
+    assert!(timeout(TIMEOUT, session.recv()).await.unwrap().is_empty());
+    session.close().await;
+}
+
+#[tokio::test]
+async fn bind_unspecified() {
+    let (client, server) = instances().await;
+    task::spawn(ekho::server::run(server));
+
+    // Not bound to loopback, where only the server itself could connect
+    let (session, bnd) = bind(&client, "0.0.0.0:0").await;
+    assert_eq!(bnd.addr, Socks5Addr::Ip(Ipv4Addr::UNSPECIFIED.into()));
+    let remote = connect_from(Ipv4Addr::LOCALHOST, &bnd).await;
+    assert_eq!(success(&session).await, remote.local_addr().unwrap().into());
+    drop(remote);
+    session.close().await;
+}
+
+#[tokio::test]
+async fn bind_unexpected_peer() {
+    let (client, server) = instances().await;
+    task::spawn(ekho::server::run(server));
+
+    let expected = Ipv4Addr::new(127, 0, 0, 2);
+    let (session, bnd) = bind(&client, &format!("{}:0", expected)).await;
+    assert!(matches!(bnd.addr, Socks5Addr::Ip(IpAddr::V4(ip)) if ip.is_loopback()));
+
+    // Connections from other hosts are closed without a reply
+    let mut stranger = connect_from(Ipv4Addr::LOCALHOST, &bnd).await;
+    let mut buf = [0; 1];
+    assert_eq!(
+        timeout(TIMEOUT, stranger.read(&mut buf))
+            .await
+            .unwrap()
+            .unwrap(),
+        0
+    );
+
+    let remote = connect_from(expected, &bnd).await;
+    let peer: SocketAddr = remote.local_addr().unwrap();
+    assert_eq!(success(&session).await, peer.into());
+    drop(remote);
+    session.close().await;
+}